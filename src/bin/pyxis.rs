@@ -1,37 +1,475 @@
-use clap::{crate_version, App, Arg};
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::{crate_version, App, Arg, ArgMatches};
+use indicatif::HumanBytes;
+use itertools::Itertools;
 use pyxis_manage::*;
+use serde::Serialize;
+use serde_json::json;
+use simplelog::{
+    ColorChoice, CombinedLogger, LevelFilter, SharedLogger, TermLogger, TerminalMode, WriteLogger,
+};
 
 fn main() {
     let matches = App::new("pyxis")
         .version(crate_version!())
         .author("chordtoll")
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .takes_value(true)
+                .global(true)
+                .help("Configuration file to use instead of ~/.pyxis/config.yaml"),
+        )
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .takes_value(true)
+                .global(true)
+                .possible_values(["text", "json"])
+                .default_value("text")
+                .help("Print results as human-readable text or as JSON"),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .multiple_occurrences(true)
+                .global(true)
+                .help("Log more detail; repeat for even more"),
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .multiple_occurrences(true)
+                .global(true)
+                .conflicts_with("verbose")
+                .help("Log only warnings; repeat to log only errors"),
+        )
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .takes_value(true)
+                .global(true)
+                .help("Also write a detailed log to this file"),
+        )
         .subcommand(
-            App::new("parcel").subcommand(
-                App::new("build").arg(
-                    Arg::new("INPUT")
-                        .required(true)
-                        .help("The package to build. provider|package."),
+            App::new("parcel")
+                .subcommand(
+                    App::new("build").arg(
+                        Arg::new("INPUT")
+                            .required(true)
+                            .help("The package to build. provider|package."),
+                    ),
+                )
+                .subcommand(App::new("list").about("List the parcels in the store"))
+                .subcommand(
+                    App::new("info")
+                        .about("Show the details of a stored parcel")
+                        .arg(
+                            Arg::new("PARCEL")
+                                .required(true)
+                                .help("The parcel to show. provider|package."),
+                        ),
                 ),
+        )
+        .subcommand(
+            App::new("store").subcommand(
+                App::new("gc")
+                    .about(
+                        "Remove parcels and cached packages that the given manifests do not need",
+                    )
+                    .arg(
+                        Arg::new("MANIFEST")
+                            .required(true)
+                            .multiple_occurrences(true)
+                            .help("Manifests whose packages are kept"),
+                    )
+                    .arg(
+                        Arg::new("dry-run")
+                            .long("dry-run")
+                            .help("List what would be removed without removing it"),
+                    )
+                    .arg(
+                        Arg::new("keep-days")
+                            .long("keep-days")
+                            .takes_value(true)
+                            .validator(|v| v.parse::<u64>())
+                            .help("Keep everything written within this many days"),
+                    ),
             ),
         )
         .subcommand(
-            App::new("image").subcommand(
-                App::new("build").arg(
+            App::new("image")
+                .subcommand(
+                    App::new("build")
+                        .arg(
+                            Arg::new("MANIFEST")
+                                .required(true)
+                                .help("The package manifest from which to build the image"),
+                        )
+                        .arg(
+                            Arg::new("jobs")
+                                .short('j')
+                                .long("jobs")
+                                .takes_value(true)
+                                .validator(|v| v.parse::<usize>())
+                                .help("How many parcels to download and build at once"),
+                        ),
+                )
+                .subcommand(
+                    App::new("resolve")
+                        .about("Show the packages a manifest pulls in, without building them")
+                        .arg(
+                            Arg::new("MANIFEST")
+                                .required(true)
+                                .help("The package manifest to resolve"),
+                        )
+                        .arg(
+                            Arg::new("missing")
+                                .long("missing")
+                                .help("Only show packages whose parcel is not in the store"),
+                        ),
+                )
+                .subcommand(
+                    App::new("graph")
+                        .about("Print the dependency graph of a manifest")
+                        .arg(
+                            Arg::new("MANIFEST")
+                                .required(true)
+                                .help("The package manifest to graph"),
+                        )
+                        .arg(
+                            Arg::new("format")
+                                .long("format")
+                                .takes_value(true)
+                                .possible_values(["dot", "json"])
+                                .help("Graphviz DOT or JSON. Defaults to the --output format."),
+                        ),
+                ),
+        )
+        .subcommand(
+            App::new("sync")
+                .about("Download the sync databases of the configured repositories")
+                .arg(
+                    Arg::new("MANIFEST")
+                        .multiple_occurrences(true)
+                        .help("Sync the repositories these manifests use instead"),
+                ),
+        )
+        .subcommand(
+            App::new("why")
+                .about("Show the dependency chains that pull a package into an image")
+                .arg(
                     Arg::new("MANIFEST")
                         .required(true)
-                        .help("The package manifest from which to build the image"),
+                        .help("The package manifest of the image"),
+                )
+                .arg(
+                    Arg::new("PACKAGE")
+                        .required(true)
+                        .help("The package to explain. provider|package or package."),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .default_value("10")
+                        .validator(|v| v.parse::<usize>())
+                        .help("Show at most this many chains, or all of them with 0"),
                 ),
-            ),
         )
         .get_matches();
+    let json = matches.value_of("output") == Some("json");
+    if let Err(e) = init_logging(&matches).and_then(|_| run(&matches, json)) {
+        if json {
+            emit(&json!({
+                "error": {
+                    "kind": e.kind(),
+                    "message": e.to_string(),
+                    "exit_code": e.exit_code(),
+                }
+            }));
+        } else {
+            eprintln!("pyxis: {}", e);
+        }
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run(matches: &ArgMatches, json: bool) -> Result<()> {
+    let mut config = Config::load(matches.value_of("config").map(Path::new))?;
     if let Some(matches) = matches.subcommand_matches("parcel") {
         if let Some(matches) = matches.subcommand_matches("build") {
-            pyxis_parcel_build_named(matches.value_of("INPUT").unwrap())
+            let package = pyxis_parcel_build_named(&config, matches.value_of("INPUT").unwrap())?;
+            if json {
+                emit(&json!({ "built": package }));
+            } else {
+                println!("Built {}", package);
+            }
+        }
+        if matches.subcommand_matches("list").is_some() {
+            let parcels = list_parcels(&config)?;
+            if json {
+                emit(&parcels);
+            } else {
+                print_parcel_list(&parcels);
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("info") {
+            let parcel = parcel_info(&config, matches.value_of("PARCEL").unwrap())?;
+            if json {
+                emit(&parcel);
+            } else {
+                print_parcel_info(&parcel);
+            }
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("store") {
+        if let Some(matches) = matches.subcommand_matches("gc") {
+            let manifests: Vec<&str> = matches.values_of("MANIFEST").unwrap().collect();
+            // clap has already checked that the value is a number
+            let keep_days = matches.value_of_t("keep-days").ok();
+            let dry_run = matches.is_present("dry-run");
+            let report = store_gc(&config, &manifests, keep_days, dry_run)?;
+            let freed = report.freed();
+            if json {
+                emit(&json!({
+                    "dry_run": dry_run,
+                    "removed": report.parcels,
+                    "removed_cached": report.cached,
+                    "freed": freed,
+                }));
+            } else {
+                let verb = if dry_run { "Would remove" } else { "Removed" };
+                for parcel in &report.parcels {
+                    println!(
+                        "{} {}|{} {} ({})",
+                        verb,
+                        parcel.provider,
+                        parcel.name,
+                        parcel.version,
+                        HumanBytes(parcel.size)
+                    );
+                }
+                for cached in &report.cached {
+                    println!(
+                        "{} cached {} ({})",
+                        verb,
+                        cached.path.display(),
+                        HumanBytes(cached.size)
+                    );
+                }
+                println!(
+                    "{} {} parcels and {} cached files, {}",
+                    verb,
+                    report.parcels.len(),
+                    report.cached.len(),
+                    HumanBytes(freed)
+                );
+            }
         }
     }
     if let Some(matches) = matches.subcommand_matches("image") {
         if let Some(matches) = matches.subcommand_matches("build") {
-            pyxis_image_build(matches.value_of("MANIFEST").unwrap())
+            if let Ok(jobs) = matches.value_of_t("jobs") {
+                config.jobs = jobs;
+            }
+            let packages = pyxis_image_build(&config, matches.value_of("MANIFEST").unwrap())?;
+            if json {
+                emit(&json!({
+                    "output_dir": config.output_dir,
+                    "packages": packages.iter().collect::<Vec<_>>(),
+                }));
+            } else {
+                println!(
+                    "Built image with {} packages in {}",
+                    packages.len(),
+                    config.output_dir.display()
+                );
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("resolve") {
+            let packages = image_resolve(&config, matches.value_of("MANIFEST").unwrap())?;
+            let missing = matches.is_present("missing");
+            let packages: Vec<_> = packages.iter().filter(|p| !missing || !p.stored).collect();
+            if json {
+                emit(&packages);
+            } else {
+                print_resolution(&packages);
+            }
         }
+        if let Some(matches) = matches.subcommand_matches("graph") {
+            let graph = image_graph(&config, matches.value_of("MANIFEST").unwrap())?;
+            let format = matches
+                .value_of("format")
+                .unwrap_or(if json { "json" } else { "dot" });
+            if format == "json" {
+                emit(&graph);
+            } else {
+                print!("{}", graph.to_dot());
+            }
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("sync") {
+        let manifests: Vec<&str> = matches
+            .values_of("MANIFEST")
+            .into_iter()
+            .flatten()
+            .collect();
+        let synced = sync_databases(&config, &manifests)?;
+        if json {
+            emit(&synced);
+        } else {
+            for db in &synced {
+                println!("Synced {} ({})", db.repo, HumanBytes(db.size));
+            }
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("why") {
+        let why = image_why(
+            &config,
+            matches.value_of("MANIFEST").unwrap(),
+            matches.value_of("PACKAGE").unwrap(),
+            matches.value_of_t("limit").unwrap(),
+        )?;
+        if json {
+            emit(&why);
+        } else {
+            for chain in &why.chains {
+                println!("{}", chain.iter().join(" -> "));
+            }
+            if why.truncated {
+                println!("... more chains not shown, raise --limit to see them");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Log to stderr at the level chosen with -v/-q, and at debug level to the log
+/// file if one is given
+fn init_logging(matches: &ArgMatches) -> Result<()> {
+    let level = match (
+        matches.occurrences_of("verbose"),
+        matches.occurrences_of("quiet"),
+    ) {
+        (0, 0) => LevelFilter::Info,
+        (1, _) => LevelFilter::Debug,
+        (v, _) if v > 1 => LevelFilter::Trace,
+        (_, 1) => LevelFilter::Warn,
+        _ => LevelFilter::Error,
+    };
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
+        level,
+        simplelog::Config::default(),
+        TerminalMode::Stderr,
+        if nix::unistd::isatty(nix::libc::STDERR_FILENO).unwrap_or(false) {
+            ColorChoice::Auto
+        } else {
+            ColorChoice::Never
+        },
+    )];
+    if let Some(path) = matches.value_of("log-file") {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        loggers.push(WriteLogger::new(
+            level.max(LevelFilter::Debug),
+            simplelog::Config::default(),
+            file,
+        ));
+    }
+    // Only fails if a logger is already set, which cannot happen here
+    let _ = CombinedLogger::init(loggers);
+    Ok(())
+}
+
+/// Print a result as JSON on stdout
+fn emit<T: Serialize + ?Sized>(value: &T) {
+    // Serializing our own result types cannot fail
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
+
+fn print_resolution(packages: &[&ResolvedPackage]) {
+    println!(
+        "{:<10} {:<32} {:<20} {:<7} FROM",
+        "PROVIDER", "NAME", "VERSION", "STORED"
+    );
+    for package in packages {
+        println!(
+            "{:<10} {:<32} {:<20} {:<7} {}",
+            package.id.provider,
+            package.id.name,
+            package.version,
+            if package.stored { "yes" } else { "no" },
+            package.entry
+        );
+        for optdep in &package.optdeps {
+            println!("    with {} ({})", optdep.dep, optdep.package);
+        }
+        for optdep in &package.available_optdeps {
+            match &optdep.description {
+                Some(description) => println!("    optional {}: {}", optdep.dep, description),
+                None => println!("    optional {}", optdep.dep),
+            }
+        }
+    }
+}
+
+fn print_parcel_list(parcels: &[ParcelInfo]) {
+    println!(
+        "{:<10} {:<32} {:<20} {:>10}  BUILT",
+        "PROVIDER", "NAME", "VERSION", "SIZE"
+    );
+    for parcel in parcels {
+        println!(
+            "{:<10} {:<32} {:<20} {:>10}  {}",
+            parcel.provider,
+            parcel.name,
+            parcel.version,
+            HumanBytes(parcel.size).to_string(),
+            format_age(parcel.built)
+        );
+    }
+}
+
+fn print_parcel_info(parcel: &ParcelInfo) {
+    println!("Provider:  {}", parcel.provider);
+    println!("Name:      {}", parcel.name);
+    println!("Version:   {}", parcel.version);
+    println!("Size:      {}", HumanBytes(parcel.size));
+    println!("Built:     {}", format_age(parcel.built));
+    println!("Scriptlet: {}", if parcel.scriptlet { "yes" } else { "no" });
+    if let Some(source) = &parcel.source {
+        println!("Source:    {}", source);
+    }
+    if parcel.depends.is_empty() {
+        println!("Depends:   none");
+    } else {
+        println!("Depends:");
+        for dep in &parcel.depends {
+            println!("  {}", dep);
+        }
+    }
+}
+
+/// Describe a time given in seconds since the epoch as how long ago it was
+fn format_age(secs: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let age = now.saturating_sub(secs);
+    match age {
+        0..=59 => format!("{}s ago", age),
+        60..=3599 => format!("{}m ago", age / 60),
+        3600..=86399 => format!("{}h ago", age / 3600),
+        _ => format!("{}d ago", age / 86400),
     }
 }
@@ -1,3 +1,6 @@
+use std::path::Path;
+
+use log::{info, trace};
 use nix::{
     fcntl::OFlag,
     poll::{PollFd, PollFlags},
@@ -5,73 +8,96 @@ use nix::{
     unistd::execv,
 };
 
-pub fn run_in_chroot(cmdline: String, input: String) -> i32 {
+use crate::{PyxisError, Result};
+
+/// Run `cmdline` with bash inside the chroot at `root`, feeding it `input` on
+/// stdin. Everything the command prints is logged line by line, tagged with
+/// `tag` to say which package or hook it came from.
+pub fn run_in_chroot(root: &Path, tag: &str, cmdline: String, input: String) -> Result<i32> {
+    let err = |e: nix::Error| PyxisError::Chroot(format!("{}: {}", cmdline, e));
     let cwdfd = nix::fcntl::open(
         ".",
         OFlag::O_RDONLY | OFlag::O_CLOEXEC,
         nix::sys::stat::Mode::empty(),
     )
-    .unwrap();
+    .map_err(err)?;
     let child2parent_pipefd = nix::sys::socket::socketpair(
         nix::sys::socket::AddressFamily::Unix,
         nix::sys::socket::SockType::Stream,
         None,
         nix::sys::socket::SockFlag::empty(),
     )
-    .unwrap();
+    .map_err(err)?;
     let parent2child_pipefd = nix::sys::socket::socketpair(
         nix::sys::socket::AddressFamily::Unix,
         nix::sys::socket::SockType::Stream,
         None,
         nix::sys::socket::SockFlag::empty(),
     )
-    .unwrap();
+    .map_err(err)?;
     match unsafe { nix::unistd::fork() } {
         Ok(nix::unistd::ForkResult::Parent { child, .. }) => {
-            nix::unistd::close(child2parent_pipefd.1).unwrap();
-            nix::unistd::close(parent2child_pipefd.0).unwrap();
+            nix::unistd::close(cwdfd).map_err(err)?;
+            nix::unistd::close(child2parent_pipefd.1).map_err(err)?;
+            nix::unistd::close(parent2child_pipefd.0).map_err(err)?;
             let mut pollfds = [
                 PollFd::new(child2parent_pipefd.0, PollFlags::POLLIN),
                 PollFd::new(parent2child_pipefd.1, PollFlags::POLLOUT),
             ];
             let mut buf = [0u8];
+            let mut line = Vec::new();
             let mut input = input.as_bytes().to_vec();
             loop {
-                nix::poll::poll(&mut pollfds, -1).unwrap();
+                nix::poll::poll(&mut pollfds, -1).map_err(err)?;
                 if let Some(flags0) = pollfds[0].revents() {
                     if let Some(flags1) = pollfds[1].revents() {
                         if flags0.contains(nix::poll::PollFlags::POLLIN) {
-                            if nix::unistd::read(child2parent_pipefd.0, &mut buf).unwrap() == 0 {
+                            if nix::unistd::read(child2parent_pipefd.0, &mut buf).map_err(err)? == 0
+                            {
                                 break;
                             }
-                            nix::unistd::write(nix::libc::STDOUT_FILENO, &buf).unwrap();
+                            if buf[0] == b'\n' {
+                                info!("[{}] {}", tag, String::from_utf8_lossy(&line));
+                                line.clear();
+                            } else {
+                                line.push(buf[0]);
+                            }
                         } else if flags1.contains(nix::poll::PollFlags::POLLOUT) {
                             if input.is_empty() {
-                                println!("Closing");
-                                nix::unistd::close(parent2child_pipefd.1).unwrap();
+                                trace!("[{}] closing input", tag);
+                                nix::unistd::close(parent2child_pipefd.1).map_err(err)?;
                                 pollfds[1] = PollFd::new(-1, PollFlags::empty());
                             } else if nix::unistd::write(parent2child_pipefd.1, &[input.remove(0)])
-                                .unwrap()
+                                .map_err(err)?
                                 == 0
                             {
                                 break;
                             }
                         } else {
-                            panic!("Poll error: {:?}", pollfds);
+                            return Err(PyxisError::Chroot(format!(
+                                "{}: poll error: {:?}",
+                                cmdline, pollfds
+                            )));
                         }
                     }
                 } else {
-                    panic!("Poll error: {:?}", pollfds);
+                    return Err(PyxisError::Chroot(format!(
+                        "{}: poll error: {:?}",
+                        cmdline, pollfds
+                    )));
                 }
             }
-            println!();
-            let res = nix::sys::wait::waitpid(child, None).unwrap();
+            if !line.is_empty() {
+                info!("[{}] {}", tag, String::from_utf8_lossy(&line));
+            }
+            let res = nix::sys::wait::waitpid(child, None).map_err(err)?;
 
             match res {
-                WaitStatus::Exited(_, code) => {
-                    return code;
-                }
-                _ => unimplemented!(),
+                WaitStatus::Exited(_, code) => Ok(code),
+                status => Err(PyxisError::Chroot(format!(
+                    "{}: unexpected child status {:?}",
+                    cmdline, status
+                ))),
             }
         }
         Ok(nix::unistd::ForkResult::Child) => {
@@ -109,19 +135,18 @@ pub fn run_in_chroot(cmdline: String, input: String) -> i32 {
 
             nix::unistd::close(cwdfd).unwrap();
 
-            nix::unistd::chroot("temp").unwrap();
+            nix::unistd::chroot(root).unwrap();
 
             nix::unistd::chdir("/").unwrap();
 
-            execv(
+            let Err(e) = execv(
                 &std::ffi::CString::new("/bin/bash").unwrap(),
                 &["/bin/bash", "-x", "-c", &cmdline].map(|x| std::ffi::CString::new(x).unwrap()),
-            )
-            .unwrap();
+            );
+            eprintln!("exec failed: {}", e);
 
             unsafe { nix::libc::_exit(1) };
         }
-        Err(_) => println!("Fork failed"),
+        Err(e) => Err(err(e)),
     }
-    panic!("We should never get here");
 }
@@ -0,0 +1,162 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    get_home,
+    pacmanconf::{PacmanConf, Repository},
+    PyxisError, Result,
+};
+
+/// Locations searched for a configuration file when none is given explicitly,
+/// relative to the user's home directory or absolute. The first one found wins.
+const USER_CONFIG: &str = ".pyxis/config.yaml";
+const SYSTEM_CONFIG: &str = "/etc/pyxis/config.yaml";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// Root of the parcel store, one subdirectory per provider
+    pub parcel_root:    PathBuf,
+    /// Root of the local recipe tree, one subdirectory per recipe
+    pub recipe_root:    PathBuf,
+    /// Mirrors serving the sync repositories, tried in order. Either base URLs
+    /// or pacman `Server =` values containing `$repo` and `$arch`.
+    pub mirrors:        Vec<String>,
+    /// How many more times to go through the mirrors when all of them fail,
+    /// waiting longer before each round
+    pub fetch_retries:  u32,
+    /// Sync repositories to register, in priority order, each served from
+    /// every mirror. Ignored when `pacman_conf` is set.
+    pub repos:          Vec<String>,
+    /// pacman.conf to read the sync repositories, their servers and their
+    /// SigLevels from, e.g. the host's /etc/pacman.conf
+    pub pacman_conf:    Option<PathBuf>,
+    /// Local package files to use instead of the sync repositories, by
+    /// package name, e.g. a custom kernel. They are trusted as they are,
+    /// without checksum or signature checks.
+    pub overrides:      BTreeMap<String, PathBuf>,
+    /// Package caches to copy packages from before downloading them, e.g. the
+    /// host's /var/cache/pacman/pkg. Packages are still cached in the store.
+    pub cache_dirs:     Vec<PathBuf>,
+    /// GnuPG home holding the keyring that package signatures are checked
+    /// against
+    pub gpg_dir:        PathBuf,
+    /// alpm database path holding the sync databases that `pyxis sync`
+    /// downloads. Defaults to a directory under `db` in the parcel store for
+    /// each set of repositories.
+    pub db_path:        Option<PathBuf>,
+    /// Directory the image is assembled in
+    pub work_dir:       PathBuf,
+    /// Directory the finished image is copied to
+    pub output_dir:     PathBuf,
+    /// How many parcels to download and build at once
+    pub jobs:           usize,
+    /// Providers tried, in order, for package names without a `provider|`
+    pub provider_order: Vec<String>,
+}
+
+/// The defaults, with the store and recipes under the user's home directory.
+/// Without a home directory those two paths are left empty, which
+/// `Config::load` reports as an error.
+impl Default for Config {
+    fn default() -> Config {
+        let home = get_home().ok();
+        let in_home = |path: &str| home.as_ref().map(|h| h.join(path)).unwrap_or_default();
+        Config {
+            parcel_root:    in_home(".pyxis/parcel"),
+            recipe_root:    in_home(".pyxis/recipe"),
+            mirrors:        vec![String::from("http://archrepo.calamityconductor.com")],
+            fetch_retries:  2,
+            repos:          vec![String::from("core"), String::from("extra")],
+            pacman_conf:    None,
+            overrides:      BTreeMap::new(),
+            cache_dirs:     Vec::new(),
+            gpg_dir:        PathBuf::from("/etc/pacman.d/gnupg"),
+            db_path:        None,
+            work_dir:       PathBuf::from("temp"),
+            output_dir:     PathBuf::from("/tmp/build-pyxis"),
+            jobs:           std::thread::available_parallelism().map_or(4, |n| n.get()),
+            provider_order: vec![String::from("local"), String::from("arch")],
+        }
+    }
+}
+
+impl Config {
+    /// The sync repositories to resolve and fetch packages from
+    pub fn repositories(&self) -> Result<Vec<Repository>> {
+        match &self.pacman_conf {
+            Some(path) => Ok(PacmanConf::load(path)?.repos),
+            None => Ok(self
+                .repos
+                .iter()
+                .map(|repo| Repository::from_mirrors(repo, &self.mirrors))
+                .collect()),
+        }
+    }
+
+    /// The alpm database path, with the sync databases in its `sync`
+    /// subdirectory. Unless `db_path` is set, it is named by a digest of the
+    /// repositories and their servers, so that configurations fetching a
+    /// repository from different mirrors keep separate copies of its database.
+    pub fn db_dir(&self) -> Result<PathBuf> {
+        if let Some(path) = &self.db_path {
+            return Ok(path.clone());
+        }
+        let mut hasher = Sha256::new();
+        for repo in self.repositories()? {
+            hasher.update(format!("{}\n", repo.name));
+            for server in &repo.servers {
+                hasher.update(format!(" {}\n", server));
+            }
+        }
+        Ok(self
+            .parcel_root
+            .join("db")
+            .join(format!("{:x}", hasher.finalize())))
+    }
+
+    /// The package cache in the store, holding every package downloaded
+    pub fn cache_dir(&self) -> PathBuf {
+        self.parcel_root.join("cache")
+    }
+
+    /// Load the configuration from `path` if given, otherwise from the first of
+    /// `~/.pyxis/config.yaml` and `/etc/pyxis/config.yaml` that exists.
+    /// Settings missing from the file keep their defaults. Without a home
+    /// directory the file must set `parcel_root` and `recipe_root` itself.
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let path = match path {
+            Some(path) => Some(path.to_owned()),
+            None => get_home()
+                .ok()
+                .map(|home| home.join(USER_CONFIG))
+                .into_iter()
+                .chain([PathBuf::from(SYSTEM_CONFIG)])
+                .find(|p| p.exists()),
+        };
+        let config: Config = match path {
+            Some(path) => {
+                let file = File::open(&path).map_err(|e| PyxisError::Config {
+                    path:   path.clone(),
+                    reason: e.to_string(),
+                })?;
+                serde_yaml::from_reader(file).map_err(|e| PyxisError::Config {
+                    path,
+                    reason: e.to_string(),
+                })?
+            }
+            None => Config::default(),
+        };
+        if config.parcel_root.as_os_str().is_empty() || config.recipe_root.as_os_str().is_empty() {
+            // Their defaults need the home directory, so say why it is missing
+            get_home()?;
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    path::Path,
+    time::Duration,
+};
+
+use indexmap::IndexMap;
+use itertools::Itertools;
+use log::{debug, warn};
+
+use crate::{Config, PyxisError, Result};
+
+/// Give up on a mirror that does not answer or stalls for this long
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Download `url` into `out`. `file://` URLs are copied from the local
+/// filesystem, so a mirror can be a plain directory.
+pub(crate) fn download(url: &str, out: &mut File) -> std::result::Result<(), String> {
+    out.set_len(0).map_err(|e| e.to_string())?;
+    out.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+    if let Some(path) = url.strip_prefix("file://") {
+        let mut src = File::open(Path::new(path)).map_err(|e| e.to_string())?;
+        std::io::copy(&mut src, out).map_err(|e| e.to_string())?;
+    } else {
+        let curl_err = |e: curl::Error| e.to_string();
+
+        let mut easy = curl::easy::Easy::new();
+        easy.url(url).map_err(curl_err)?;
+        easy.follow_location(true).map_err(curl_err)?;
+        easy.fail_on_error(true).map_err(curl_err)?;
+        easy.connect_timeout(TIMEOUT).map_err(curl_err)?;
+        easy.low_speed_limit(1).map_err(curl_err)?;
+        easy.low_speed_time(TIMEOUT).map_err(curl_err)?;
+
+        let mut transfer = easy.transfer();
+        transfer
+            .write_function(|data| {
+                // Returning a short count makes curl abort the transfer
+                match out.write_all(data) {
+                    Ok(()) => Ok(data.len()),
+                    Err(_) => Ok(0),
+                }
+            })
+            .map_err(curl_err)?;
+        transfer.perform().map_err(curl_err)?;
+    }
+    out.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Download `filename` from the first of `servers` that has it into `out`,
+/// returning the URL it came from. When every server fails, go through them
+/// again up to `fetch_retries` times, backing off between rounds. `what`
+/// names the download in errors.
+pub(crate) fn download_mirrored(
+    config: &Config,
+    what: &str,
+    servers: &[String],
+    filename: &str,
+    out: &mut File,
+) -> Result<String> {
+    if servers.is_empty() {
+        return Err(PyxisError::fetch(what, "no mirrors configured"));
+    }
+    // The last error from each mirror, in the order they were tried
+    let mut failures = IndexMap::new();
+    for round in 0..=config.fetch_retries {
+        if round > 0 {
+            let delay = Duration::from_secs(1 << (round - 1).min(6));
+            warn!(
+                "All mirrors failed for {}, retrying in {}s",
+                what,
+                delay.as_secs()
+            );
+            std::thread::sleep(delay);
+        }
+        for server in servers {
+            let url = format!("{}/{}", server, filename);
+            debug!("Downloading {}", url);
+            match download(&url, out) {
+                Ok(()) => return Ok(url),
+                Err(e) => {
+                    warn!("{}: {}", url, e);
+                    failures.insert(url, e);
+                }
+            }
+        }
+    }
+    Err(PyxisError::fetch(
+        what,
+        format!(
+            "every mirror failed: {}",
+            failures
+                .iter()
+                .map(|(url, e)| format!("{}: {}", url, e))
+                .join("; ")
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read};
+
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            fetch_retries: 0,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn mirrored_fails_over_to_next_mirror() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = dir.path().join("good");
+        fs::create_dir(&good).unwrap();
+        fs::write(good.join("core.db"), "database").unwrap();
+        let servers = vec![
+            format!("file://{}", dir.path().join("missing").display()),
+            format!("file://{}", good.display()),
+        ];
+
+        let mut out = tempfile::tempfile().unwrap();
+        let url = download_mirrored(&config(), "core", &servers, "core.db", &mut out).unwrap();
+        assert_eq!(url, format!("{}/core.db", servers[1]));
+        let mut text = String::new();
+        out.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "database");
+    }
+
+    #[test]
+    fn mirrored_reports_every_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let servers = vec![
+            format!("file://{}", dir.path().join("a").display()),
+            format!("file://{}", dir.path().join("b").display()),
+        ];
+
+        let mut out = tempfile::tempfile().unwrap();
+        match download_mirrored(&config(), "core", &servers, "core.db", &mut out) {
+            Err(PyxisError::Fetch { package, reason }) => {
+                assert_eq!(package, "core");
+                for server in &servers {
+                    assert!(
+                        reason.contains(&format!("{}/core.db", server)),
+                        "{}",
+                        reason
+                    );
+                }
+            }
+            other => panic!("expected a fetch error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mirrored_needs_a_mirror() {
+        let mut out = tempfile::tempfile().unwrap();
+        assert!(download_mirrored(&config(), "core", &[], "core.db", &mut out).is_err());
+    }
+}
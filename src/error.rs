@@ -0,0 +1,133 @@
+use std::{fmt, path::PathBuf};
+
+#[derive(Debug)]
+pub enum PyxisError {
+    /// A package spec, provider or dependency could not be resolved
+    Resolution(String),
+    /// A package or sync database could not be downloaded
+    Fetch { package: String, reason: String },
+    /// A parcel could not be read, written or extracted
+    ParcelIo { path: PathBuf, reason: String },
+    /// A hook file is malformed or uses unsupported features
+    HookParse { path: PathBuf, reason: String },
+    /// A command could not be run inside the image chroot
+    Chroot(String),
+    /// A filesystem could not be mounted into the image
+    Mount { target: PathBuf, reason: String },
+    /// The configuration file could not be loaded
+    Config { path: PathBuf, reason: String },
+    /// A manifest is malformed
+    Manifest { path: PathBuf, reason: String },
+    /// A package recipe exists but cannot be read or is malformed
+    Recipe { path: PathBuf, reason: String },
+    /// The user or their home directory cannot be determined
+    Environment(String),
+    /// Any other I/O failure
+    Io(std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, PyxisError>;
+
+impl PyxisError {
+    /// Process exit code the CLI reports for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PyxisError::Io(_) => 1,
+            PyxisError::Resolution(_) => 2,
+            PyxisError::Fetch { .. } => 3,
+            PyxisError::ParcelIo { .. } => 4,
+            PyxisError::HookParse { .. } => 5,
+            PyxisError::Chroot(_) => 6,
+            PyxisError::Mount { .. } => 7,
+            PyxisError::Config { .. } => 8,
+            PyxisError::Manifest { .. } => 9,
+            PyxisError::Recipe { .. } => 10,
+            PyxisError::Environment(_) => 11,
+        }
+    }
+
+    /// Short machine-readable name of the error kind
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PyxisError::Io(_) => "io",
+            PyxisError::Resolution(_) => "resolution",
+            PyxisError::Fetch { .. } => "fetch",
+            PyxisError::ParcelIo { .. } => "parcel_io",
+            PyxisError::HookParse { .. } => "hook_parse",
+            PyxisError::Chroot(_) => "chroot",
+            PyxisError::Mount { .. } => "mount",
+            PyxisError::Config { .. } => "config",
+            PyxisError::Manifest { .. } => "manifest",
+            PyxisError::Recipe { .. } => "recipe",
+            PyxisError::Environment(_) => "environment",
+        }
+    }
+
+    pub(crate) fn parcel_io(path: impl Into<PathBuf>, reason: impl fmt::Display) -> PyxisError {
+        PyxisError::ParcelIo {
+            path:   path.into(),
+            reason: reason.to_string(),
+        }
+    }
+
+    pub(crate) fn fetch(package: &str, reason: impl fmt::Display) -> PyxisError {
+        PyxisError::Fetch {
+            package: package.to_owned(),
+            reason:  reason.to_string(),
+        }
+    }
+
+    pub(crate) fn mount(target: impl Into<PathBuf>, reason: impl fmt::Display) -> PyxisError {
+        PyxisError::Mount {
+            target: target.into(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for PyxisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PyxisError::Resolution(msg) => write!(f, "resolution failed: {}", msg),
+            PyxisError::Fetch { package, reason } => {
+                write!(f, "could not fetch {}: {}", package, reason)
+            }
+            PyxisError::ParcelIo { path, reason } => {
+                write!(f, "parcel I/O error on {}: {}", path.display(), reason)
+            }
+            PyxisError::HookParse { path, reason } => {
+                write!(f, "bad hook file {}: {}", path.display(), reason)
+            }
+            PyxisError::Chroot(msg) => write!(f, "chroot command failed: {}", msg),
+            PyxisError::Mount { target, reason } => {
+                write!(f, "could not mount {}: {}", target.display(), reason)
+            }
+            PyxisError::Config { path, reason } => {
+                write!(f, "bad config file {}: {}", path.display(), reason)
+            }
+            PyxisError::Manifest { path, reason } => {
+                write!(f, "bad manifest {}: {}", path.display(), reason)
+            }
+            PyxisError::Recipe { path, reason } => {
+                write!(f, "bad recipe {}: {}", path.display(), reason)
+            }
+            PyxisError::Environment(msg) => write!(f, "bad environment: {}", msg),
+            PyxisError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PyxisError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PyxisError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PyxisError {
+    fn from(e: std::io::Error) -> PyxisError {
+        PyxisError::Io(e)
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    path::Path,
+};
+
+use serde::Serialize;
+
+use crate::{
+    get_provider,
+    imagebuild::{resolve_configured, resolve_image_packages},
+    Config, Manifest, PackageId, PyxisError, Result,
+};
+
+#[derive(Serialize)]
+pub struct GraphNode {
+    pub id:       String,
+    pub provider: String,
+    pub name:     String,
+    pub version:  String,
+    /// Whether the manifest names this package itself
+    pub root:     bool,
+}
+
+#[derive(Serialize)]
+pub struct GraphEdge {
+    pub from: String,
+    pub to:   String,
+}
+
+/// The dependency graph of a manifest, serializable as JSON or rendered as
+/// Graphviz DOT
+#[derive(Serialize)]
+pub struct ImageGraph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+/// Build the dependency graph of a manifest, with one node per package in its
+/// closure and one edge per direct dependency
+pub fn image_graph(config: &Config, manifest: &str) -> Result<ImageGraph> {
+    let manifest = Manifest::load(Path::new(manifest))?;
+    let config = &manifest.configure(config);
+    let resolution = resolve_configured(config, &manifest)?;
+    let mut graph = ImageGraph {
+        nodes: Vec::new(),
+        edges: Vec::new(),
+    };
+    for package in &resolution.packages {
+        graph.nodes.push(GraphNode {
+            id:       package.to_string(),
+            provider: package.provider.clone(),
+            name:     package.name.clone(),
+            version:  get_provider(&package.provider)?.get_version(config, &package.name)?,
+            root:     resolution.roots.contains(package),
+        });
+        for dep in resolution.deps.get(package).into_iter().flatten() {
+            graph.edges.push(GraphEdge {
+                from: package.to_string(),
+                to:   dep.to_string(),
+            });
+        }
+    }
+    Ok(graph)
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl ImageGraph {
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph image {\n");
+        for node in &self.nodes {
+            let label = format!("{}|{}\\n{}", node.provider, node.name, node.version);
+            let shape = if node.root { ", shape=box" } else { "" };
+            // Writing to a String cannot fail
+            let _ = writeln!(
+                out,
+                "    {} [label=\"{}\"{}];",
+                quote(&node.id),
+                label.replace('"', "\\\""),
+                shape
+            );
+        }
+        for edge in &self.edges {
+            let _ = writeln!(out, "    {} -> {};", quote(&edge.from), quote(&edge.to));
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// Dependency chains that pull a package into an image
+#[derive(Serialize)]
+pub struct WhyChains {
+    /// Chains from a package the manifest names to the package. The first
+    /// chain from each root is a shortest one.
+    pub chains:    Vec<Vec<PackageId>>,
+    /// Whether more chains were left out than the limit allows
+    pub truncated: bool,
+}
+
+/// Find up to `limit` dependency chains, or all of them if `limit` is 0, from
+/// a package named by the manifest to `package`, which may be given as
+/// `provider|name` or as a bare name
+pub fn image_why(
+    config: &Config,
+    manifest: &str,
+    package: &str,
+    limit: usize,
+) -> Result<WhyChains> {
+    let resolution = resolve_image_packages(config, &Manifest::load(Path::new(manifest))?)?;
+    let matches = |p: &PackageId| match package.contains('|') {
+        true => p.to_string() == package,
+        false => p.name == package,
+    };
+    if !resolution.packages.iter().any(matches) {
+        return Err(PyxisError::Resolution(format!(
+            "{} is not part of the image",
+            package
+        )));
+    }
+    let reaching = packages_reaching(
+        &resolution.deps,
+        resolution.packages.iter().filter(|p| matches(p)),
+    );
+    // The number of chains can grow exponentially with the size of the
+    // image, so stop looking once one more than the limit has been found
+    let limit = if limit == 0 { usize::MAX } else { limit };
+    let mut chains = Vec::new();
+    for root in resolution
+        .roots
+        .iter()
+        .filter(|r| reaching.contains_key(*r))
+    {
+        let mut path = vec![root.clone()];
+        walk_chains(
+            &resolution.deps,
+            &reaching,
+            &matches,
+            limit.saturating_add(1),
+            &mut path,
+            &mut chains,
+        );
+    }
+    let truncated = chains.len() > limit;
+    chains.truncate(limit);
+    Ok(WhyChains { chains, truncated })
+}
+
+/// Every package from which one of `targets` can be reached, the targets
+/// included, with the length of its shortest chain to one of them. Found by
+/// walking the dependency edges backwards.
+fn packages_reaching<'a>(
+    deps: &HashMap<PackageId, Vec<PackageId>>,
+    targets: impl Iterator<Item = &'a PackageId>,
+) -> HashMap<PackageId, usize> {
+    let mut dependents: HashMap<&PackageId, Vec<&PackageId>> = HashMap::new();
+    for (package, package_deps) in deps {
+        for dep in package_deps {
+            dependents.entry(dep).or_default().push(package);
+        }
+    }
+    let mut reached: HashMap<PackageId, usize> = HashMap::new();
+    let mut queue: VecDeque<(&PackageId, usize)> = targets.map(|t| (t, 0)).collect();
+    while let Some((package, distance)) = queue.pop_front() {
+        if !reached.contains_key(package) {
+            reached.insert(package.clone(), distance);
+            queue.extend(
+                dependents
+                    .get(package)
+                    .into_iter()
+                    .flatten()
+                    .map(|p| (*p, distance + 1)),
+            );
+        }
+    }
+    reached
+}
+
+/// Extend `path` along every dependency that leads to a match, recording each
+/// chain that ends in one until `chains` holds `limit` of them. Only packages
+/// in `reaching` are entered, nearest to a match first, so the first chain
+/// found from any package is a shortest one.
+fn walk_chains<F: Fn(&PackageId) -> bool>(
+    deps: &HashMap<PackageId, Vec<PackageId>>,
+    reaching: &HashMap<PackageId, usize>,
+    matches: &F,
+    limit: usize,
+    path: &mut Vec<PackageId>,
+    chains: &mut Vec<Vec<PackageId>>,
+) {
+    let current = path.last().unwrap().clone();
+    if matches(&current) {
+        chains.push(path.clone());
+        return;
+    }
+    let mut next: Vec<(&PackageId, usize)> = deps
+        .get(&current)
+        .into_iter()
+        .flatten()
+        // Dependency cycles would otherwise never end
+        .filter(|dep| !path.contains(dep))
+        .filter_map(|dep| reaching.get(dep).map(|distance| (dep, *distance)))
+        .collect();
+    next.sort_by_key(|(_, distance)| *distance);
+    for (dep, _) in next {
+        if chains.len() >= limit {
+            return;
+        }
+        path.push(dep.clone());
+        walk_chains(deps, reaching, matches, limit, path, chains);
+        path.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(name: &str) -> PackageId {
+        PackageId::new("arch", name)
+    }
+
+    fn deps(edges: &[(&str, &[&str])]) -> HashMap<PackageId, Vec<PackageId>> {
+        edges
+            .iter()
+            .map(|(from, to)| (id(from), to.iter().map(|t| id(t)).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn dot_output() {
+        let graph = ImageGraph {
+            nodes: vec![
+                GraphNode {
+                    id:       "arch|bash".to_owned(),
+                    provider: "arch".to_owned(),
+                    name:     "bash".to_owned(),
+                    version:  "5.1-1".to_owned(),
+                    root:     true,
+                },
+                GraphNode {
+                    id:       "arch|glibc".to_owned(),
+                    provider: "arch".to_owned(),
+                    name:     "glibc".to_owned(),
+                    version:  "2.35-2".to_owned(),
+                    root:     false,
+                },
+            ],
+            edges: vec![GraphEdge {
+                from: "arch|bash".to_owned(),
+                to:   "arch|glibc".to_owned(),
+            }],
+        };
+        assert_eq!(
+            graph.to_dot(),
+            "digraph image {\n    \
+             \"arch|bash\" [label=\"arch|bash\\n5.1-1\", shape=box];\n    \
+             \"arch|glibc\" [label=\"arch|glibc\\n2.35-2\"];\n    \
+             \"arch|bash\" -> \"arch|glibc\";\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn dot_quotes_ids() {
+        assert_eq!(quote("a\"b\\c"), "\"a\\\"b\\\\c\"");
+    }
+
+    /// The chains from `base` to `glibc`, as package names
+    fn chains(deps: &HashMap<PackageId, Vec<PackageId>>, limit: usize) -> Vec<Vec<String>> {
+        let matches = |p: &PackageId| p.name == "glibc";
+        let reaching = packages_reaching(deps, [id("glibc")].iter());
+        let mut chains = Vec::new();
+        walk_chains(
+            deps,
+            &reaching,
+            &matches,
+            limit,
+            &mut vec![id("base")],
+            &mut chains,
+        );
+        chains
+            .iter()
+            .map(|c| c.iter().map(|p| p.name.clone()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn chains_through_diamond_and_cycle() {
+        let deps = deps(&[
+            ("base", &["bash", "coreutils"]),
+            ("bash", &["readline", "glibc"]),
+            ("readline", &["bash", "glibc"]),
+            ("coreutils", &["glibc", "acl"]),
+            ("acl", &[]),
+        ]);
+        let reaching = packages_reaching(&deps, [id("glibc")].iter());
+        assert_eq!(reaching.get(&id("base")), Some(&2));
+        assert!(!reaching.contains_key(&id("acl")));
+
+        assert_eq!(
+            chains(&deps, usize::MAX),
+            vec![
+                vec!["base", "bash", "glibc"],
+                vec!["base", "bash", "readline", "glibc"],
+                vec!["base", "coreutils", "glibc"],
+            ]
+        );
+    }
+
+    #[test]
+    fn chains_stop_at_limit() {
+        // Every layer doubles the number of chains
+        let mut edges = vec![("base".to_owned(), vec!["a0".to_owned(), "b0".to_owned()])];
+        for layer in 0..40 {
+            let next = match layer {
+                39 => vec!["glibc".to_owned()],
+                _ => vec![format!("a{}", layer + 1), format!("b{}", layer + 1)],
+            };
+            edges.push((format!("a{}", layer), next.clone()));
+            edges.push((format!("b{}", layer), next));
+        }
+        let deps = edges
+            .iter()
+            .map(|(from, to)| (id(from), to.iter().map(|t| id(t)).collect()))
+            .collect();
+
+        let chains = chains(&deps, 3);
+        assert_eq!(chains.len(), 3);
+        assert!(chains.iter().all(|c| c.len() == 42));
+    }
+}
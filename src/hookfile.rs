@@ -1,8 +1,11 @@
 use std::{
     fs::File,
     io::{BufRead, BufReader},
+    path::Path,
 };
 
+use crate::{PyxisError, Result};
+
 #[derive(Eq, PartialEq, Debug)]
 pub struct Hook {
     pub triggers: Vec<HookTrigger>,
@@ -79,40 +82,34 @@ pub enum HookActionWhen {
     None,
 }
 
-pub fn parse_hook(f: &mut File) -> Hook {
+pub fn parse_hook(path: &Path) -> Result<Hook> {
+    let err = |reason: String| PyxisError::HookParse {
+        path: path.to_owned(),
+        reason,
+    };
+    let f = File::open(path).map_err(|e| err(e.to_string()))?;
+
     let mut res = Hook::new();
     let mut section = 0;
     let mut ct = HookTrigger::new();
     let mut ca = HookAction::new();
     for line in BufReader::new(f).lines() {
-        let line = line.unwrap().trim().to_string();
+        let line = line.map_err(|e| err(e.to_string()))?.trim().to_string();
 
         if line.is_empty() {
             continue;
         }
 
-        if line == "[Trigger]" {
-            section = 1;
+        if line == "[Trigger]" || line == "[Action]" {
+            section = if line == "[Trigger]" { 1 } else { 2 };
             if ct.flavor != HookTriggerFlavor::None {
                 res.triggers.push(ct);
                 ct = HookTrigger::new();
             }
             if ca.when != HookActionWhen::None {
-                assert_eq!(res.action.when, HookActionWhen::None);
-                res.action = ca;
-                ca = HookAction::new();
-            }
-            continue;
-        }
-
-        if line == "[Action]" {
-            section = 2;
-            if ct.flavor != HookTriggerFlavor::None {
-                res.triggers.push(ct);
-                ct = HookTrigger::new();
-            }
-            if ca.when != HookActionWhen::None {
-                assert_eq!(res.action.when, HookActionWhen::None);
+                if res.action.when != HookActionWhen::None {
+                    return Err(err(String::from("more than one action block")));
+                }
                 res.action = ca;
                 ca = HookAction::new();
             }
@@ -125,65 +122,67 @@ pub fn parse_hook(f: &mut File) -> Hook {
             let vs = ls[1..].join("=").trim().to_owned();
             let v = vs.as_str();
             match section {
-                0 => unimplemented!("Outside of block"),
                 1 => match k {
                     "Type" => match v {
                         "Path" => ct.flavor = HookTriggerFlavor::Path,
                         "File" => ct.flavor = HookTriggerFlavor::Path,
                         "Package" => ct.flavor = HookTriggerFlavor::Package,
-                        _ => unimplemented!("Unknown value for trigger block {}: {}", k, v),
+                        _ => return Err(err(format!("unknown value for trigger {}: {}", k, v))),
                     },
                     "Operation" => match v {
                         "Install" => ct.operations.push(HookTriggerOperation::Install),
                         "Upgrade" => ct.operations.push(HookTriggerOperation::Upgrade),
                         "Remove" => ct.operations.push(HookTriggerOperation::Remove),
-                        _ => unimplemented!("Unknown value for trigger block {}: {}", k, v),
+                        _ => return Err(err(format!("unknown value for trigger {}: {}", k, v))),
                     },
                     "Target" => {
                         ct.targets.push(v.to_string());
                     }
-                    _ => unimplemented!("Unknown key for trigger block: {}", k),
+                    _ => return Err(err(format!("unknown key for trigger block: {}", k))),
                 },
                 2 => match k {
                     "Description" => ca.description = Some(v.to_string()),
                     "When" => match v {
                         "PreTransaction" => ca.when = HookActionWhen::PreTransaction,
                         "PostTransaction" => ca.when = HookActionWhen::PostTransaction,
-                        _ => unimplemented!("Unknown value for action block {}: {}", k, v),
+                        _ => return Err(err(format!("unknown value for action {}: {}", k, v))),
                     },
                     "Exec" => ca.exec = v.to_string(),
-                    _ => unimplemented!("Unknown key for action block: {}", k),
+                    _ => return Err(err(format!("unknown key for action block: {}", k))),
                 },
-                _ => unimplemented!(),
+                _ => return Err(err(format!("'{}' outside of a block", line))),
             }
             continue;
         }
         if ls.len() == 1 {
             let k = ls[0].trim();
             match section {
-                0 => unimplemented!("Outside of block"),
-                1 => unimplemented!("Unknown key for trigger block: {}", k),
+                1 => return Err(err(format!("unknown key for trigger block: {}", k))),
                 2 => match k {
                     "NeedsTargets" => ca.needs_targets = true,
-                    _ => unimplemented!("Unknown key for action block: {}", k),
+                    _ => return Err(err(format!("unknown key for action block: {}", k))),
                 },
-                _ => unimplemented!(),
+                _ => return Err(err(format!("'{}' outside of a block", line))),
             }
             continue;
         }
-
-        unimplemented!("Unexpected line: '{}'", line);
     }
 
     if ct.flavor != HookTriggerFlavor::None {
         res.triggers.push(ct);
     }
     if ca.when != HookActionWhen::None {
-        assert_eq!(res.action.when, HookActionWhen::None);
+        if res.action.when != HookActionWhen::None {
+            return Err(err(String::from("more than one action block")));
+        }
         res.action = ca;
     }
 
-    assert!(!res.triggers.is_empty());
-    assert_ne!(res.action.when, HookActionWhen::None);
-    res
+    if res.triggers.is_empty() {
+        return Err(err(String::from("no triggers")));
+    }
+    if res.action.when == HookActionWhen::None {
+        return Err(err(String::from("no action")));
+    }
+    Ok(res)
 }
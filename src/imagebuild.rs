@@ -1,45 +1,215 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fs::File,
-    io::{BufRead, BufReader, Write},
+    io::Write,
     os::unix::prelude::OsStrExt,
     path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
 };
 
 use indexmap::IndexSet;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{debug, info, trace, warn};
 use pyxis_parcel::{InodeKind, ParcelHandle, ReaderWriter};
-use sys_mount::Unmount;
+use serde::Serialize;
+use sys_mount::{Mount, MountFlags, Unmount, UnmountDrop, UnmountFlags};
 
 use crate::{
-    chroot::run_in_chroot, get_deps, get_parcel_path, get_provider, hookfile, pyxis_parcel_build,
-    ParcelProvider,
+    chroot::run_in_chroot, exists_parcel, get_deps, get_parcel_path, get_provider, hookfile,
+    parcel_current, providers::upper, pyxis_parcel_build, relations::Chosen, spec::parse_dep,
+    store, Config, Manifest, ManifestEntry, OptDep, OptDepSelection, PackageId, PackageSpec,
+    PyxisError, Result,
 };
 
-pub fn get_image_packages(manifest: &str) -> IndexSet<(ParcelProvider, String)> {
-    let f = File::open(manifest).unwrap();
-    let br = BufReader::new(f);
+/// The closure of a manifest
+pub struct Resolution {
+    /// Every package in the closure, in install order
+    pub packages: IndexSet<PackageId>,
+    /// The manifest entry that first pulled in each package
+    pub entries:  HashMap<PackageId, String>,
+    /// The packages named by the manifest itself, in manifest order
+    pub roots:    IndexSet<PackageId>,
+    /// The direct dependencies of each package
+    pub deps:     HashMap<PackageId, Vec<PackageId>>,
+    /// The optional dependencies the manifest selected for each package
+    pub optdeps:  HashMap<PackageId, Vec<SelectedOptDep>>,
+}
+
+/// An optional dependency the manifest asked for, and the package chosen
+/// for it
+#[derive(Serialize, Debug, Clone)]
+pub struct SelectedOptDep {
+    pub dep:     String,
+    pub package: PackageId,
+}
+
+/// A package in the closure of a manifest, as shown by `pyxis image resolve`
+#[derive(Serialize)]
+pub struct ResolvedPackage {
+    pub id:                PackageId,
+    pub version:           String,
+    /// The manifest entry that pulled the package in
+    pub entry:             String,
+    /// Whether the store already has the package's parcel at this version
+    pub stored:            bool,
+    /// The optional dependencies the manifest selected
+    pub optdeps:           Vec<SelectedOptDep>,
+    /// The optional dependencies available but not selected
+    pub available_optdeps: Vec<OptDep>,
+}
+
+/// Resolve a manifest and build a parcel for every package in its closure,
+/// returning the packages in install order
+pub fn get_image_packages(config: &Config, manifest: &str) -> Result<IndexSet<PackageId>> {
+    let manifest = Manifest::load(Path::new(manifest))?;
+    build_image_packages(&manifest.configure(config), &manifest)
+}
+
+/// `get_image_packages` for a loaded manifest, with `config` already
+/// configured by it
+fn build_image_packages(config: &Config, manifest: &Manifest) -> Result<IndexSet<PackageId>> {
+    let to_install = resolve_configured(config, manifest)?.packages;
+    build_parcels(config, &to_install)?;
+    Ok(to_install)
+}
+
+/// Build the parcels for `packages` on `config.jobs` threads, stopping at the
+/// first failure
+fn build_parcels(config: &Config, packages: &IndexSet<PackageId>) -> Result<()> {
+    let mut missing = Vec::new();
+    for package in packages {
+        if !parcel_current(config, package)? {
+            missing.push(package);
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    info!("Building {} parcels", missing.len());
+    let sty = ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] {wide_bar} {pos:>5}/{len:5} {msg:>25}")
+        .progress_chars("##-");
+    let pb = progress_bar(missing.len(), sty);
+    let next = AtomicUsize::new(0);
+    let failure = Mutex::new(None);
+    std::thread::scope(|scope| {
+        for _ in 0..config.jobs.clamp(1, missing.len()) {
+            scope.spawn(|| {
+                while failure.lock().unwrap().is_none() {
+                    let package = match missing.get(next.fetch_add(1, Ordering::SeqCst)) {
+                        Some(package) => package,
+                        None => break,
+                    };
+                    match pyxis_parcel_build(config, package) {
+                        Ok(()) => {
+                            pb.set_message(package.name.clone());
+                            pb.inc(1);
+                        }
+                        Err(e) => {
+                            failure.lock().unwrap().get_or_insert(e);
+                        }
+                    }
+                }
+            });
+        }
+    });
+    pb.finish();
+    match failure.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
 
+/// Resolve a manifest and describe every package in its closure, without
+/// fetching or building anything
+pub fn image_resolve(config: &Config, manifest: &str) -> Result<Vec<ResolvedPackage>> {
+    let manifest = Manifest::load(Path::new(manifest))?;
+    let config = &manifest.configure(config);
+    let mut resolution = resolve_configured(config, &manifest)?;
+    resolution
+        .packages
+        .into_iter()
+        .map(|id| {
+            let provider = get_provider(&id.provider)?;
+            let optdeps = resolution.optdeps.remove(&id).unwrap_or_default();
+            let available_optdeps = provider
+                .optdeps(config, &id.name)?
+                .into_iter()
+                .filter(|o| !optdeps.iter().any(|s| s.dep == o.dep))
+                .collect();
+            Ok(ResolvedPackage {
+                version: provider.get_version(config, &id.name)?,
+                entry: resolution.entries.remove(&id).unwrap_or_default(),
+                stored: parcel_current(config, &id)?,
+                optdeps,
+                available_optdeps,
+                id,
+            })
+        })
+        .collect()
+}
+
+/// Resolve a manifest to its closure of packages in install order, without
+/// building anything
+pub fn resolve_image_packages(config: &Config, manifest: &Manifest) -> Result<Resolution> {
+    resolve_configured(&manifest.configure(config), manifest)
+}
+
+/// `resolve_image_packages` with `config` already configured by the manifest
+pub(crate) fn resolve_configured(config: &Config, manifest: &Manifest) -> Result<Resolution> {
     let mut to_install = IndexSet::new();
+    let mut entries = HashMap::new();
+    let mut roots = IndexSet::new();
+    let mut deps = HashMap::new();
     let mut dep_stack = Vec::new();
     let mut visited = HashSet::new();
+    let mut chosen = Chosen::default();
 
-    for line in br.lines() {
-        let l = line.unwrap();
-        if l.starts_with('#') {
-            continue;
+    // Choose what the manifest names first, so that its choices satisfy the
+    // dependencies of everything else
+    let mut resolved_roots = Vec::new();
+    for entry in &manifest.entries {
+        for spec in expand_entry(config, entry)? {
+            let root = chosen.resolve(config, &spec)?;
+            roots.insert(root.clone());
+            resolved_roots.push((entry, root));
+        }
+    }
+    let mut optdeps = HashMap::new();
+    for (entry, root) in &resolved_roots {
+        let selected = select_optdeps(config, root, &entry.optdeps, &mut chosen)?;
+        if !selected.is_empty() {
+            optdeps.insert(root.clone(), selected);
         }
-        let (provider, package) = get_provider(&l);
-        dep_stack.push((provider, package));
+    }
+    for (entry, root) in resolved_roots {
+        let entry = &entry.spec;
+        dep_stack.push(root);
         while let Some(package) = dep_stack.pop() {
             if to_install.contains(&package) {
                 continue;
             }
             let mut to_push = Vec::new();
-            for dep in get_deps(package.0, package.1.clone()) {
-                if !to_install.contains(&dep) {
-                    to_push.push(dep)
+            if !deps.contains_key(&package) {
+                let mut package_deps = get_deps(config, &package, &mut chosen)?;
+                for optdep in optdeps.get(&package).into_iter().flatten() {
+                    if !package_deps.contains(&optdep.package) {
+                        package_deps.push(optdep.package.clone());
+                    }
+                }
+                deps.insert(package.clone(), package_deps);
+            }
+            for dep in &deps[&package] {
+                if !to_install.contains(dep) {
+                    to_push.push(dep.clone())
                 }
             }
+            entries
+                .entry(package.clone())
+                .or_insert_with(|| entry.clone());
             if to_push.is_empty() {
                 to_install.insert(package);
             } else if visited.contains(&package) {
@@ -52,153 +222,314 @@ pub fn get_image_packages(manifest: &str) -> IndexSet<(ParcelProvider, String)>
             }
         }
     }
+    chosen.check_conflicts()?;
+    Ok(Resolution {
+        packages: to_install,
+        entries,
+        roots,
+        deps,
+        optdeps,
+    })
+}
+
+/// The specs a manifest entry stands for: the entry itself, or the members of
+/// the group it names without the excluded ones. Groups without a provider go
+/// to the first provider in `config.provider_order` that has them.
+fn expand_entry(config: &Config, entry: &ManifestEntry) -> Result<Vec<PackageSpec>> {
+    let spec = PackageSpec::parse(&entry.spec)?;
+    let group = match spec.name.strip_prefix('@') {
+        Some(group) => group,
+        None => return Ok(vec![spec]),
+    };
+    if spec.constraint.is_some() {
+        return Err(PyxisError::Resolution(format!(
+            "group '{}' cannot take a version constraint",
+            spec
+        )));
+    }
+    let (provider, members) = match &spec.provider {
+        Some(provider) => (
+            provider.clone(),
+            get_provider(provider)?.group_members(config, group)?,
+        ),
+        None => {
+            let mut reasons = Vec::new();
+            let mut found = None;
+            for provider in &config.provider_order {
+                match get_provider(provider)?.group_members(config, group) {
+                    Ok(members) => {
+                        found = Some((provider.clone(), members));
+                        break;
+                    }
+                    Err(PyxisError::Resolution(msg)) => {
+                        reasons.push(format!("{}: {}", provider, msg))
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            found.ok_or_else(|| {
+                PyxisError::Resolution(format!(
+                    "no provider has group '{}' ({})",
+                    group,
+                    reasons.join("; ")
+                ))
+            })?
+        }
+    };
+    if let Some(missing) = entry.exclude.iter().find(|m| !members.contains(m)) {
+        return Err(PyxisError::Resolution(format!(
+            "cannot exclude {}: it is not in group {}",
+            missing, group
+        )));
+    }
+    debug!(
+        "Group {}|@{} has {} members",
+        provider,
+        group,
+        members.len()
+    );
+    Ok(members
+        .iter()
+        .filter(|m| !entry.exclude.contains(m))
+        .map(|m| PackageSpec::new(&provider, m, None))
+        .collect())
+}
+
+/// Resolve the optional dependencies of a package that the manifest selected
+fn select_optdeps(
+    config: &Config,
+    package: &PackageId,
+    selection: &OptDepSelection,
+    chosen: &mut Chosen,
+) -> Result<Vec<SelectedOptDep>> {
+    if *selection == OptDepSelection::None {
+        return Ok(Vec::new());
+    }
+    let available = get_provider(&package.provider)?.optdeps(config, &package.name)?;
+    let wanted: Vec<&OptDep> = match selection {
+        OptDepSelection::None => Vec::new(),
+        OptDepSelection::All => available.iter().collect(),
+        OptDepSelection::Only(names) => names
+            .iter()
+            .map(|name| {
+                available
+                    .iter()
+                    .find(|o| optdep_name(o) == name)
+                    .ok_or_else(|| {
+                        PyxisError::Resolution(format!(
+                            "{} is not an optional dependency of {}",
+                            name, package
+                        ))
+                    })
+            })
+            .collect::<Result<_>>()?,
+    };
+    wanted
+        .into_iter()
+        .map(|optdep| {
+            let (name, constraint) = parse_dep(&optdep.dep)?;
+            let spec = PackageSpec::new(&package.provider, &name, constraint);
+            let resolved = chosen.resolve_dep(config, &spec).map_err(|e| match e {
+                PyxisError::Resolution(msg) => {
+                    PyxisError::Resolution(format!("{} (optional dependency of {})", msg, package))
+                }
+                e => e,
+            })?;
+            Ok(SelectedOptDep {
+                dep:     optdep.dep.clone(),
+                package: resolved,
+            })
+        })
+        .collect()
+}
+
+/// The package name an optional dependency refers to, without any version
+fn optdep_name(optdep: &OptDep) -> &str {
+    optdep.dep.split(['<', '>', '=']).next().unwrap_or_default()
+}
 
-    for (provider, package) in &to_install {
-        pyxis_parcel_build(*provider, package);
+/// A progress bar on stderr, hidden when stdout is not a terminal so that
+/// piped and machine-readable output stays clean
+fn progress_bar(len: usize, style: ProgressStyle) -> ProgressBar {
+    let pb = ProgressBar::new(len as u64);
+    if !nix::unistd::isatty(nix::libc::STDOUT_FILENO).unwrap_or(false) {
+        pb.set_draw_target(ProgressDrawTarget::hidden());
     }
-    to_install
+    pb.set_style(style);
+    pb
+}
+
+fn mount_fs(
+    source: &str,
+    target: &Path,
+    fstype: &str,
+    flags: MountFlags,
+    data: Option<&str>,
+) -> Result<UnmountDrop<Mount>> {
+    Mount::new(source, target, fstype, flags, data)
+        .map(|m| m.into_unmount_drop(UnmountFlags::DETACH))
+        .map_err(|e| PyxisError::mount(target, e))
 }
 
-pub fn pyxis_image_build(manifest: &str) {
-    let to_install = get_image_packages(manifest);
+/// Build the image for a manifest into `config.output_dir`, returning the
+/// packages it contains in install order
+pub fn pyxis_image_build(config: &Config, manifest: &str) -> Result<IndexSet<PackageId>> {
+    let manifest = Manifest::load(Path::new(manifest))?;
+    let config = &manifest.configure(config);
+    // Keep the parcels this image uses from being collected while it builds
+    let _store = store::lock_store(config, false)?;
+    let to_install = build_image_packages(config, &manifest)?;
+    let packages: Vec<PackageId> = to_install.iter().cloned().collect();
+    let upper_name = upper::upper_name(config, &packages)?;
+    let root = config.work_dir.as_path();
 
-    let sty = indicatif::ProgressStyle::default_bar()
+    let sty = ProgressStyle::default_bar()
         .template("[{elapsed_precise}] {wide_bar} {pos:>5}/{len:5} {msg:>25}")
         .progress_chars("##-");
-    let mount_result = sys_mount::Mount::new(
-        "tmpfs",
-        "temp",
-        "tmpfs",
-        sys_mount::MountFlags::empty(),
-        Some("size=5G"),
-    );
+    let mount = mount_fs("tmpfs", root, "tmpfs", MountFlags::empty(), Some("size=5G"))?;
 
-    let mount = mount_result
-        .unwrap()
-        .into_unmount_drop(sys_mount::UnmountFlags::DETACH);
-
-    println!("Extracting packages");
-    let pb = indicatif::ProgressBar::new(to_install.len() as u64);
-    pb.set_style(sty.clone());
-    for (provider, package) in &to_install {
-        pb.set_message(package.clone());
+    info!("Extracting packages");
+    let pb = progress_bar(to_install.len(), sty.clone());
+    for package in &to_install {
+        pb.set_message(package.name.clone());
         pb.tick();
-        let f = File::open(get_parcel_path(*provider, package))
-            .unwrap_or_else(|_| panic!("Could not find parcel {}", package));
-        let reader = Box::new(ReaderWriter::new(f));
-        let mut parcel = ParcelHandle::load(reader).unwrap();
-        let mut f = File::open(get_parcel_path(*provider, package)).unwrap();
-        extract_parcel(&mut f, &mut parcel, 1, "temp");
+        extract_stored_parcel(config, package, root)?;
         pb.inc(1);
     }
     pb.finish();
 
-    let proc_mount = sys_mount::Mount::new(
+    let upper_id = PackageId::new(upper::PREFIX, &upper_name);
+    let _upper = store::lock_parcel(config, &upper_id)?;
+    if exists_parcel(config, upper::PREFIX, &upper_name) {
+        info!("Reusing upper parcel {}", upper_name);
+        extract_stored_parcel(config, &upper_id, root)?;
+    } else {
+        let before = upper::snapshot(root)?;
+        if run_actions(root, &to_install, sty)? {
+            info!("Capturing upper parcel {}", upper_name);
+            upper::capture(config, root, &before, &upper_name, &packages)?;
+        } else {
+            warn!(
+                "Not capturing upper parcel {} since an action failed",
+                upper_name
+            );
+        }
+    }
+
+    let status = std::process::Command::new("rsync")
+        .args(["-ah", "--delete"])
+        .arg(format!("{}/", root.display()))
+        .arg(format!("{}/", config.output_dir.display()))
+        .status()?;
+    if !status.success() {
+        return Err(PyxisError::Io(std::io::Error::other(format!(
+            "rsync to {} failed: {}",
+            config.output_dir.display(),
+            status
+        ))));
+    }
+
+    std::mem::drop(mount);
+    Ok(to_install)
+}
+
+/// Run the install scriptlets of every package, then the alpm hooks they
+/// trigger, with the API filesystems mounted into the image. Returns whether
+/// every one of them succeeded.
+fn run_actions(root: &Path, to_install: &IndexSet<PackageId>, sty: ProgressStyle) -> Result<bool> {
+    let proc_mount = mount_fs(
         "proc",
-        "temp/proc",
+        &root.join("proc"),
         "proc",
-        sys_mount::MountFlags::NOSUID
-            | sys_mount::MountFlags::NOEXEC
-            | sys_mount::MountFlags::NODEV,
+        MountFlags::NOSUID | MountFlags::NOEXEC | MountFlags::NODEV,
         None,
-    )
-    .unwrap()
-    .into_unmount_drop(sys_mount::UnmountFlags::DETACH);
+    )?;
 
-    let sys_mount = sys_mount::Mount::new(
+    let sys_mount = mount_fs(
         "sys",
-        "temp/sys",
+        &root.join("sys"),
         "sysfs",
-        sys_mount::MountFlags::NOSUID
-            | sys_mount::MountFlags::NOEXEC
-            | sys_mount::MountFlags::NODEV
-            | sys_mount::MountFlags::RDONLY,
+        MountFlags::NOSUID | MountFlags::NOEXEC | MountFlags::NODEV | MountFlags::RDONLY,
         None,
-    )
-    .unwrap()
-    .into_unmount_drop(sys_mount::UnmountFlags::DETACH);
+    )?;
 
-    let dev_mount = sys_mount::Mount::new(
+    let dev_mount = mount_fs(
         "udev",
-        "temp/dev",
+        &root.join("dev"),
         "devtmpfs",
-        sys_mount::MountFlags::NOSUID,
+        MountFlags::NOSUID,
         Some("mode=0755"),
-    )
-    .unwrap()
-    .into_unmount_drop(sys_mount::UnmountFlags::DETACH);
+    )?;
 
-    let devpts_mount = sys_mount::Mount::new(
+    let devpts_mount = mount_fs(
         "devpts",
-        "temp/dev/pts",
+        &root.join("dev/pts"),
         "devpts",
-        sys_mount::MountFlags::NOSUID | sys_mount::MountFlags::NOEXEC,
+        MountFlags::NOSUID | MountFlags::NOEXEC,
         Some("mode=0620,gid=5"),
-    )
-    .unwrap()
-    .into_unmount_drop(sys_mount::UnmountFlags::DETACH);
+    )?;
 
-    let devshm_mount = sys_mount::Mount::new(
+    let devshm_mount = mount_fs(
         "shm",
-        "temp/dev/shm",
+        &root.join("dev/shm"),
         "tmpfs",
-        sys_mount::MountFlags::NOSUID | sys_mount::MountFlags::NODEV,
+        MountFlags::NOSUID | MountFlags::NODEV,
         Some("mode=1777"),
-    )
-    .unwrap()
-    .into_unmount_drop(sys_mount::UnmountFlags::DETACH);
+    )?;
 
-    let tmp_mount = sys_mount::Mount::new(
+    let tmp_mount = mount_fs(
         "tmp",
-        "temp/tmp",
+        &root.join("tmp"),
         "tmpfs",
-        sys_mount::MountFlags::NOSUID
-            | sys_mount::MountFlags::NODEV
-            | sys_mount::MountFlags::STRICTATIME,
+        MountFlags::NOSUID | MountFlags::NODEV | MountFlags::STRICTATIME,
         Some("mode=1777"),
-    )
-    .unwrap()
-    .into_unmount_drop(sys_mount::UnmountFlags::DETACH);
-
-    println!("Running actions");
-    let pb = indicatif::ProgressBar::new(to_install.len() as u64);
-    pb.set_style(sty);
-    for (provider, package) in &to_install {
-        pb.set_message(package.clone());
+    )?;
+
+    let mut succeeded = true;
+    info!("Running actions");
+    let pb = progress_bar(to_install.len(), sty);
+    for package in to_install {
+        pb.set_message(package.name.clone());
         pb.tick();
 
-        if !Path::new(&format!(
-            "temp/.PYXIS/{}/{}/.INSTALL",
-            provider.as_str(),
-            package
-        ))
-        .exists()
+        if !root
+            .join(format!(
+                ".PYXIS/{}/{}/.INSTALL",
+                package.provider, package.name
+            ))
+            .exists()
         {
             continue;
         } else {
-            pb.println(format!("Found scriptlets for {:?}|{}", provider, package));
+            debug!("Found scriptlets for {}", package);
         }
 
-        let cmdline = format!(". /.PYXIS/{}/{}/.INSTALL; declare -F post_install && post_install {} || echo No install action",provider.as_str(),package,"0");
+        let cmdline = format!(
+            ". /.PYXIS/{}/{}/.INSTALL; if declare -F post_install >/dev/null; then post_install {}; fi",
+            package.provider, package.name, "0"
+        );
 
-        run_in_chroot(cmdline, "".to_string());
+        succeeded &= run_action(root, &package.to_string(), cmdline, "".to_string())?;
         pb.inc(1);
     }
     pb.finish();
-    println!("Running hooks");
+    info!("Running hooks");
 
-    let mut hooks = if let Ok(h) = std::fs::read_dir("temp/usr/share/libalpm/hooks/") {
+    let mut hooks = if let Ok(h) = std::fs::read_dir(root.join("usr/share/libalpm/hooks/")) {
         h.map(|res| res.map(|e| e.path()))
-            .collect::<Result<Vec<_>, std::io::Error>>()
-            .unwrap()
+            .collect::<std::result::Result<Vec<_>, std::io::Error>>()?
     } else {
-        println!("No hooks directory");
+        debug!("No hooks directory");
         Vec::new()
     };
     hooks.sort();
-    for hook in hooks {
-        let hook = hookfile::parse_hook(&mut File::open(hook).unwrap());
-        println!("{:?}", hook.action.description);
+    for hook_path in hooks {
+        let hook = hookfile::parse_hook(&hook_path)?;
+        debug!(
+            "Checking hook {}: {}",
+            hook_path.display(),
+            hook.action.description.as_deref().unwrap_or("")
+        );
         let mut triggers = Vec::new();
         for trigger in hook.triggers {
             if trigger
@@ -207,20 +538,29 @@ pub fn pyxis_image_build(manifest: &str) {
             {
                 if trigger.flavor == hookfile::HookTriggerFlavor::Package {
                     for pkg in trigger.targets {
-                        if to_install.iter().any(|i| i.1 == pkg) {
-                            println!("Package hook {} triggered", pkg);
+                        if to_install.iter().any(|i| i.name == pkg) {
+                            debug!("Package hook {} triggered", pkg);
                             triggers.push(pkg);
                         }
                     }
                 } else {
                     'hookloop: for path in trigger.targets {
-                        for res in glob::glob(&("temp/".to_owned() + &path)).unwrap() {
-                            let res = res.unwrap().into_os_string().into_string().unwrap()[4..]
-                                .to_owned();
-                            println!("Path hook '{}' triggered on '{}'", path, res);
+                        let pattern = root.join(&path);
+                        let matches = glob::glob(&pattern.to_string_lossy()).map_err(|e| {
+                            PyxisError::HookParse {
+                                path:   hook_path.clone(),
+                                reason: format!("bad target '{}': {}", path, e),
+                            }
+                        })?;
+                        for res in matches.flatten() {
+                            let res = match res.strip_prefix(root) {
+                                Ok(rel) => Path::new("/").join(rel).to_string_lossy().into_owned(),
+                                Err(_) => continue,
+                            };
+                            debug!("Path hook '{}' triggered on '{}'", path, res);
                             triggers.push(res);
                             if !hook.action.needs_targets {
-                                println!("Stopping check, do not need full target list");
+                                trace!("Stopping check, do not need full target list");
                                 break 'hookloop;
                             }
                         }
@@ -229,13 +569,32 @@ pub fn pyxis_image_build(manifest: &str) {
             }
         }
         if !triggers.is_empty() {
-            assert_eq!(hook.action.when, hookfile::HookActionWhen::PostTransaction);
-            assert!(hook.action.depends.is_empty());
-            assert!(!hook.action.abort_on_fail);
+            let unsupported = if hook.action.when != hookfile::HookActionWhen::PostTransaction {
+                Some("only PostTransaction hooks are supported")
+            } else if !hook.action.depends.is_empty() {
+                Some("hook dependencies are not supported")
+            } else if hook.action.abort_on_fail {
+                Some("AbortOnFail is not supported")
+            } else {
+                None
+            };
+            if let Some(reason) = unsupported {
+                return Err(PyxisError::HookParse {
+                    path:   hook_path,
+                    reason: reason.to_owned(),
+                });
+            }
+            let hook_tag = format!(
+                "hook {}",
+                hook_path
+                    .file_stem()
+                    .map(|s| s.to_string_lossy())
+                    .unwrap_or_default()
+            );
             if hook.action.needs_targets {
-                run_in_chroot(hook.action.exec, triggers.join("\n"));
+                succeeded &= run_action(root, &hook_tag, hook.action.exec, triggers.join("\n"))?;
             } else {
-                run_in_chroot(hook.action.exec, "".to_string());
+                succeeded &= run_action(root, &hook_tag, hook.action.exec, "".to_string())?;
             }
         }
     }
@@ -246,67 +605,110 @@ pub fn pyxis_image_build(manifest: &str) {
     std::mem::drop(devpts_mount);
     std::mem::drop(devshm_mount);
     std::mem::drop(tmp_mount);
+    Ok(succeeded)
+}
 
-    std::process::Command::new("rsync")
-        .args(["-ah", "--delete", "temp/", "/tmp/build-pyxis/"])
-        .status()
-        .expect("failed to execute process");
+/// Run a scriptlet or hook in the image, returning whether it succeeded. As in
+/// pacman, a non-zero exit is only a warning.
+fn run_action(root: &Path, tag: &str, cmdline: String, input: String) -> Result<bool> {
+    match run_in_chroot(root, tag, cmdline, input)? {
+        0 => Ok(true),
+        code => {
+            warn!("{} exited with status {}", tag, code);
+            Ok(false)
+        }
+    }
+}
 
-    std::mem::drop(mount);
+fn extract_stored_parcel(config: &Config, package: &PackageId, root: &Path) -> Result<()> {
+    let path = get_parcel_path(config, &package.provider, &package.name);
+    let f = File::open(&path).map_err(|e| PyxisError::parcel_io(&path, e))?;
+    let reader = Box::new(ReaderWriter::new(f));
+    let mut parcel = ParcelHandle::load(reader).map_err(|e| PyxisError::parcel_io(&path, e))?;
+    extract_parcel(&mut parcel, 1, root)
 }
 
-fn extract_parcel(pf: &mut File, parcel: &mut ParcelHandle, ino: u64, ex_dir: &str) {
+fn extract_parcel(parcel: &mut ParcelHandle, ino: u64, ex_dir: &Path) -> Result<()> {
+    let pio = |e| PyxisError::parcel_io(ex_dir, e);
     if std::fs::metadata(ex_dir).is_err() {
-        std::fs::create_dir(ex_dir).unwrap();
+        std::fs::create_dir(ex_dir).map_err(pio)?;
     }
-    let attr = parcel.getattr(ino).unwrap();
+    let attr = parcel
+        .getattr(ino)
+        .ok_or_else(|| PyxisError::parcel_io(ex_dir, "missing inode"))?;
     std::fs::set_permissions(
         ex_dir,
         std::os::unix::fs::PermissionsExt::from_mode(attr.perm.into()),
     )
-    .unwrap();
+    .map_err(pio)?;
     nix::unistd::chown(
         ex_dir,
         Some(nix::unistd::Uid::from_raw(attr.uid)),
         Some(nix::unistd::Gid::from_raw(attr.gid)),
     )
-    .unwrap();
-    for (ino, kind, name) in parcel.readdir(ino).unwrap() {
+    .map_err(|e| PyxisError::parcel_io(ex_dir, e))?;
+    let entries = parcel
+        .readdir(ino)
+        .ok_or_else(|| PyxisError::parcel_io(ex_dir, "not a directory"))?;
+    for (ino, kind, name) in entries {
+        let fnm = ex_dir.join(&name);
         match kind {
             InodeKind::Directory => {
-                extract_parcel(pf, parcel, ino, &(String::from(ex_dir) + "/" + &name));
+                extract_parcel(parcel, ino, &fnm)?;
             }
             InodeKind::RegularFile => {
-                let fnm = String::from(ex_dir) + "/" + &name;
-                let mut f = File::create(fnm.clone()).unwrap();
-                f.write_all(&parcel.read(ino, 0, None).unwrap()).unwrap();
-                let attr = parcel.getattr(ino).unwrap();
+                let pio = |e| PyxisError::parcel_io(&fnm, e);
+                remove_existing(&fnm).map_err(pio)?;
+                let mut f = File::create(&fnm).map_err(pio)?;
+                f.write_all(
+                    &parcel
+                        .read(ino, 0, None)
+                        .map_err(|e| PyxisError::parcel_io(&fnm, e))?,
+                )
+                .map_err(pio)?;
+                let attr = parcel
+                    .getattr(ino)
+                    .ok_or_else(|| PyxisError::parcel_io(&fnm, "missing inode"))?;
                 nix::unistd::chown(
-                    fnm.as_str(),
+                    &fnm,
                     Some(nix::unistd::Uid::from_raw(attr.uid)),
                     Some(nix::unistd::Gid::from_raw(attr.gid)),
                 )
-                .unwrap();
+                .map_err(|e| PyxisError::parcel_io(&fnm, e))?;
                 std::fs::set_permissions(
-                    fnm.clone(),
+                    &fnm,
                     std::os::unix::fs::PermissionsExt::from_mode(attr.perm.into()),
                 )
-                .unwrap();
+                .map_err(pio)?;
             }
             InodeKind::Symlink => {
-                let fnm = String::from(ex_dir) + "/" + &name;
-                std::os::unix::fs::symlink(
-                    Path::new(std::ffi::OsStr::from_bytes(&parcel.readlink(ino).unwrap())),
-                    fnm.clone(),
-                )
-                .unwrap();
-            }
-            InodeKind::CharDevice => {
-                unimplemented!();
+                let target = parcel
+                    .readlink(ino)
+                    .ok_or_else(|| PyxisError::parcel_io(&fnm, "missing symlink target"))?;
+                remove_existing(&fnm).map_err(|e| PyxisError::parcel_io(&fnm, e))?;
+                std::os::unix::fs::symlink(Path::new(std::ffi::OsStr::from_bytes(&target)), &fnm)
+                    .map_err(|e| PyxisError::parcel_io(&fnm, e))?;
             }
             InodeKind::Whiteout => {
-                unimplemented!();
+                remove_existing(&fnm).map_err(|e| PyxisError::parcel_io(&fnm, e))?;
+            }
+            InodeKind::CharDevice => {
+                return Err(PyxisError::parcel_io(
+                    &fnm,
+                    format!("cannot extract {:?} entries", kind),
+                ));
             }
         }
     }
+    Ok(())
+}
+
+/// Remove whatever is at `path` so a parcel entry can take its place
+fn remove_existing(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
 }
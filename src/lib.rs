@@ -1,93 +1,199 @@
-use std::path::PathBuf;
+use std::{fmt, path::PathBuf};
+
+use itertools::Itertools;
+use log::info;
+use relations::Chosen;
+use serde::{Serialize, Serializer};
 
 mod chroot;
+mod config;
+mod download;
+mod error;
+mod graph;
 mod hookfile;
 mod imagebuild;
+mod manifest;
+mod package;
+mod pacmanconf;
 mod providers;
+mod relations;
+mod spec;
+mod store;
+mod sync;
 
-pub use imagebuild::{get_image_packages, pyxis_image_build};
+pub use config::Config;
+pub use error::{PyxisError, Result};
+pub use graph::{image_graph, image_why, GraphEdge, GraphNode, ImageGraph, WhyChains};
+pub use imagebuild::{
+    get_image_packages, image_resolve, pyxis_image_build, resolve_image_packages, Resolution,
+    ResolvedPackage, SelectedOptDep,
+};
+pub use manifest::{Manifest, ManifestEntry, OptDepSelection};
+pub use pacmanconf::{PacmanConf, Repository};
+pub use providers::{get_provider, register_provider, OptDep, Provider, Relations};
+pub use spec::{ConstraintOp, PackageSpec, VersionConstraint};
+pub use store::{
+    list_parcels, parcel_info, store_gc, write_parcel, CachedFile, GcReport, ParcelInfo,
+};
+pub use sync::{sync_databases, SyncedDb};
 
-pub fn get_user() -> String {
-    if let Ok(u) = std::env::var("SUDO_USER") {
-        u
-    } else {
-        std::env::var("USER").unwrap()
-    }
+/// The user pyxis runs for: the one who invoked sudo, if it was, otherwise
+/// `$USER`
+pub fn get_user() -> Result<String> {
+    std::env::var("SUDO_USER")
+        .or_else(|_| std::env::var("USER"))
+        .map_err(|_| {
+            PyxisError::Environment(
+                "cannot tell the user: neither $SUDO_USER nor $USER is set".to_owned(),
+            )
+        })
 }
 
-pub fn get_home() -> PathBuf {
-    PathBuf::from(passwd::Passwd::from_name(&get_user()).unwrap().home_dir)
+/// The home directory of `get_user()` from the password database, falling
+/// back to `$HOME` when the user is unknown
+pub fn get_home() -> Result<PathBuf> {
+    let user = get_user();
+    if let Some(entry) = user
+        .as_ref()
+        .ok()
+        .and_then(|user| passwd::Passwd::from_name(user))
+    {
+        return Ok(PathBuf::from(entry.home_dir));
+    }
+    if let Some(home) = std::env::var_os("HOME") {
+        return Ok(PathBuf::from(home));
+    }
+    Err(PyxisError::Environment(match user {
+        Ok(user) => format!(
+            "cannot find the home directory: {} has no password entry and $HOME is not set",
+            user
+        ),
+        Err(_) => {
+            "cannot find the home directory: none of $SUDO_USER, $USER and $HOME is set".to_owned()
+        }
+    }))
 }
 
-pub fn get_parcel_path(provider: ParcelProvider, package: &str) -> PathBuf {
-    let mut buf = get_home();
-    buf.push(".pyxis/parcel/");
-    buf.push(provider.as_str());
+pub fn get_parcel_path(config: &Config, provider: &str, package: &str) -> PathBuf {
+    let mut buf = config.parcel_root.clone();
+    buf.push(provider);
     buf.push(format!("{}.parcel", package));
     buf
 }
 
-fn exists_parcel(provider: ParcelProvider, package: &str) -> bool {
-    get_parcel_path(provider, package).exists()
+fn exists_parcel(config: &Config, provider: &str, package: &str) -> bool {
+    get_parcel_path(config, provider, package).exists()
 }
 
-#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
-pub enum ParcelProvider {
-    Arch,
-    Local,
-    Upper,
+/// Whether the store holds a parcel for a package at the version and from the
+/// source its provider offers now. Any other parcel has to be rebuilt.
+fn parcel_current(config: &Config, package: &PackageId) -> Result<bool> {
+    let stored = match store::stored_parcel(config, package)? {
+        Some(stored) => stored,
+        None => return Ok(false),
+    };
+    let provider = get_provider(&package.provider)?;
+    Ok(
+        stored.version == provider.get_version(config, &package.name)?
+            && stored.source == provider.source(config, &package.name)?,
+    )
 }
 
-impl ParcelProvider {
-    pub fn as_str(self) -> &'static str {
-        match self {
-            ParcelProvider::Arch => "arch",
-            ParcelProvider::Local => "local",
-            ParcelProvider::Upper => "Upper",
+/// A package as offered by one provider, written `provider|name`
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone)]
+pub struct PackageId {
+    pub provider: String,
+    pub name:     String,
+}
+
+impl PackageId {
+    pub fn new(provider: &str, name: &str) -> PackageId {
+        PackageId {
+            provider: provider.to_owned(),
+            name:     name.to_owned(),
         }
     }
+
+    /// Parse a `provider|name` spec
+    pub fn parse(package: &str) -> Result<PackageId> {
+        let (provider, name) = package.split_once('|').ok_or_else(|| {
+            PyxisError::Resolution(format!("'{}' does not name a provider", package))
+        })?;
+        if name.contains('|') {
+            return Err(PyxisError::Resolution(format!(
+                "'{}' is not of the form provider|package",
+                package
+            )));
+        }
+        Ok(PackageId::new(provider, name))
+    }
+}
+
+impl Serialize for PackageId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
 }
 
-fn get_provider(package: &str) -> (ParcelProvider, String) {
-    if package.contains('|') {
-        assert_eq!(package.matches('|').count(), 1);
-        let mut package = package.split('|');
-        let provider = package.next().unwrap();
-        let package = package.next().unwrap();
-        let provider = match provider {
-            "arch" => ParcelProvider::Arch,
-            "local" => ParcelProvider::Local,
-            _ => unimplemented!(),
-        };
-        (provider, package.to_owned())
-    } else {
-        unimplemented!();
+impl fmt::Display for PackageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}|{}", self.provider, self.name)
     }
 }
 
-pub fn pyxis_parcel_build_named(package: &str) {
-    let (provider, package) = get_provider(package);
-    pyxis_parcel_build(provider, &package);
+/// Resolve a spec and build its parcel, returning the package that was built
+pub fn pyxis_parcel_build_named(config: &Config, package: &str) -> Result<PackageId> {
+    let package = resolve_spec(config, &PackageSpec::parse(package)?)?;
+    pyxis_parcel_build(config, &package)?;
+    Ok(package)
 }
 
-fn pyxis_parcel_build(provider: ParcelProvider, package: &str) {
-    match provider {
-        ParcelProvider::Arch => providers::alpm::parcel_build(package),
-        ParcelProvider::Local => providers::local::parcel_build(package),
-        ParcelProvider::Upper => panic!(),
+/// Find the package that satisfies a spec, including its version constraint.
+/// Specs without a provider go to the first provider in
+/// `config.provider_order` that can satisfy them.
+fn resolve_spec(config: &Config, spec: &PackageSpec) -> Result<PackageId> {
+    let constraint = spec.constraint.as_ref();
+    if let Some(provider) = &spec.provider {
+        let name = get_provider(provider)?.resolve(config, &spec.name, constraint)?;
+        return Ok(PackageId::new(provider, &name));
     }
+    let mut reasons = Vec::new();
+    for provider in &config.provider_order {
+        match get_provider(provider)?.resolve(config, &spec.name, constraint) {
+            Ok(name) => {
+                let package = PackageId::new(provider, &name);
+                info!("Resolved {} to {}", spec, package);
+                return Ok(package);
+            }
+            Err(PyxisError::Resolution(msg)) => reasons.push(format!("{}: {}", provider, msg)),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(PyxisError::Resolution(format!(
+        "no provider satisfies '{}' ({})",
+        spec,
+        reasons.join("; ")
+    )))
+}
+
+fn pyxis_parcel_build(config: &Config, package: &PackageId) -> Result<()> {
+    let _store = store::lock_store(config, false)?;
+    let _parcel = store::lock_parcel(config, package)?;
+    get_provider(&package.provider)?.parcel_build(config, &package.name)
 }
 
-fn get_deps(provider: ParcelProvider, package: String) -> Vec<(ParcelProvider, String)> {
-    match provider {
-        ParcelProvider::Arch => providers::alpm::get_deps(&package)
-            .iter()
-            .map(|x| (provider, x.to_owned()))
-            .collect(),
-        ParcelProvider::Local => providers::local::get_deps(&package)
-            .iter()
-            .map(|x| get_provider(x))
-            .collect(),
-        ParcelProvider::Upper => panic!(),
+/// Resolve the dependencies of a package, preferring packages already chosen
+/// for the image that satisfy them, and add them to the chosen packages
+fn get_deps(config: &Config, package: &PackageId, chosen: &mut Chosen) -> Result<Vec<PackageId>> {
+    let mut deps = Vec::new();
+    for dep in get_provider(&package.provider)?.get_deps(config, &package.name)? {
+        let dep = chosen.resolve_dep(config, &dep).map_err(|e| match e {
+            PyxisError::Resolution(msg) => {
+                PyxisError::Resolution(format!("{} (required by {})", msg, package))
+            }
+            e => e,
+        })?;
+        deps.push(dep);
     }
+    Ok(deps.into_iter().unique().collect())
 }
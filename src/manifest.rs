@@ -0,0 +1,226 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use crate::{Config, PyxisError, Result};
+
+/// An image manifest: one package spec per line, `#` comments, and
+/// directives starting with `!` that adjust how the image is resolved.
+///
+/// A spec may be followed by `+optdeps` to also install all of the package's
+/// optional dependencies, or by `+optdeps=a,b` to install only those named.
+///
+/// A spec naming `@group`, e.g. `arch|@xorg`, stands for every member of the
+/// package group. Members can be left out with `-name`, as in
+/// `arch|@xorg -xorg-docs`.
+///
+/// Directives:
+///
+/// * `!pacman-conf <path>` reads the sync repositories from a pacman.conf,
+///   relative to the manifest
+/// * `!override <name>=<path>` builds package `name` from a local
+///   `.pkg.tar.*` file instead of the sync repositories, relative to the
+///   manifest
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    pub path:        PathBuf,
+    /// The package entries, in manifest order
+    pub entries:     Vec<ManifestEntry>,
+    pub pacman_conf: Option<PathBuf>,
+    /// Package overrides, added to those in the configuration
+    pub overrides:   BTreeMap<String, PathBuf>,
+}
+
+/// Which optional dependencies of a manifest entry to install
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OptDepSelection {
+    None,
+    All,
+    /// Only the optional dependencies with these names
+    Only(Vec<String>),
+}
+
+/// One package line of a manifest
+#[derive(Debug, Clone)]
+pub struct ManifestEntry {
+    /// The package spec, e.g. `arch|python-matplotlib` or `arch|@xorg`
+    pub spec:    String,
+    pub optdeps: OptDepSelection,
+    /// Group members to leave out
+    pub exclude: Vec<String>,
+}
+
+impl ManifestEntry {
+    fn parse(line: &str) -> std::result::Result<ManifestEntry, String> {
+        let mut words = line.split_whitespace();
+        let mut entry = ManifestEntry {
+            spec:    words.next().unwrap_or_default().to_owned(),
+            optdeps: OptDepSelection::None,
+            exclude: Vec::new(),
+        };
+        for word in words {
+            if let Some(member) = word.strip_prefix('-').filter(|m| !m.is_empty()) {
+                if !entry.is_group() {
+                    return Err(format!(
+                        "'{}' excludes from a spec that is not a group",
+                        line
+                    ));
+                }
+                entry.exclude.push(member.to_owned());
+                continue;
+            }
+            if entry.is_group() {
+                return Err(format!(
+                    "'{}' gives a group an option it does not take",
+                    line
+                ));
+            }
+            entry.optdeps = match word.split_once('=') {
+                None if word == "+optdeps" => OptDepSelection::All,
+                Some(("+optdeps", names)) if !names.is_empty() => {
+                    OptDepSelection::Only(names.split(',').map(str::to_owned).collect())
+                }
+                _ => return Err(format!("unknown option '{}' in '{}'", word, line)),
+            };
+        }
+        Ok(entry)
+    }
+
+    /// Whether the entry names a package group
+    pub fn is_group(&self) -> bool {
+        self.spec
+            .rsplit('|')
+            .next()
+            .is_some_and(|name| name.starts_with('@'))
+    }
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Manifest> {
+        let bad = |reason: String| PyxisError::Manifest {
+            path: path.to_owned(),
+            reason,
+        };
+        let file = File::open(path).map_err(|e| bad(e.to_string()))?;
+        let mut manifest = Manifest {
+            path:        path.to_owned(),
+            entries:     Vec::new(),
+            pacman_conf: None,
+            overrides:   BTreeMap::new(),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| bad(e.to_string()))?;
+            let line = line.trim();
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+            let directive = match line.strip_prefix('!') {
+                Some(directive) => directive,
+                None => {
+                    manifest
+                        .entries
+                        .push(ManifestEntry::parse(line).map_err(bad)?);
+                    continue;
+                }
+            };
+            let (name, arg) = directive
+                .split_once(char::is_whitespace)
+                .map(|(name, arg)| (name, arg.trim()))
+                .unwrap_or((directive, ""));
+            match name {
+                "pacman-conf" if !arg.is_empty() => {
+                    manifest.pacman_conf = Some(manifest.relative(arg));
+                }
+                "override" => {
+                    let (package, path) = arg
+                        .split_once('=')
+                        .map(|(package, path)| (package.trim(), path.trim()))
+                        .filter(|(package, path)| !package.is_empty() && !path.is_empty())
+                        .ok_or_else(|| {
+                            bad(format!("expected '!override name=path', got '{}'", line))
+                        })?;
+                    let path = manifest.relative(path);
+                    manifest.overrides.insert(package.to_owned(), path);
+                }
+                _ => return Err(bad(format!("unknown directive '{}'", line))),
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Resolve a path given in the manifest against the manifest's directory
+    fn relative(&self, path: &str) -> PathBuf {
+        self.path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(path)
+    }
+
+    /// The configuration to resolve and build this manifest with
+    pub fn configure(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        if let Some(path) = &self.pacman_conf {
+            config.pacman_conf = Some(path.clone());
+        }
+        config.overrides.extend(self.overrides.clone());
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_plain() {
+        let entry = ManifestEntry::parse("arch|glibc>=2.35").unwrap();
+        assert_eq!(entry.spec, "arch|glibc>=2.35");
+        assert_eq!(entry.optdeps, OptDepSelection::None);
+        assert!(entry.exclude.is_empty());
+    }
+
+    #[test]
+    fn entry_all_optdeps() {
+        let entry = ManifestEntry::parse("arch|python-matplotlib +optdeps").unwrap();
+        assert_eq!(entry.spec, "arch|python-matplotlib");
+        assert_eq!(entry.optdeps, OptDepSelection::All);
+    }
+
+    #[test]
+    fn entry_named_optdeps() {
+        let entry =
+            ManifestEntry::parse("arch|python-matplotlib +optdeps=tk,python-pyqt5").unwrap();
+        assert_eq!(
+            entry.optdeps,
+            OptDepSelection::Only(vec!["tk".to_owned(), "python-pyqt5".to_owned()])
+        );
+    }
+
+    #[test]
+    fn entry_bad_options() {
+        for line in [
+            "arch|foo +optdeps=",
+            "arch|foo +debug",
+            "arch|foo -bar",
+            "arch|@xorg +optdeps",
+        ] {
+            assert!(ManifestEntry::parse(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn entry_group_excludes() {
+        let entry = ManifestEntry::parse("arch|@xorg -xorg-docs -xorg-fonts").unwrap();
+        assert!(entry.is_group());
+        assert_eq!(entry.exclude, vec!["xorg-docs", "xorg-fonts"]);
+    }
+
+    #[test]
+    fn entry_group_without_provider() {
+        assert!(ManifestEntry::parse("@xorg").unwrap().is_group());
+        assert!(!ManifestEntry::parse("arch|xorg").unwrap().is_group());
+    }
+}
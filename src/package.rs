@@ -0,0 +1,289 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command, Stdio},
+    sync::Mutex,
+};
+
+use lazy_static::lazy_static;
+
+use crate::{OptDep, PyxisError, Relations, Result};
+
+lazy_static! {
+    static ref PKGINFO_CACHE: Mutex<HashMap<PathBuf, PkgInfo>> = Mutex::new(HashMap::new());
+}
+
+/// Where a tar header keeps its `ustar` magic
+const TAR_MAGIC_OFFSET: usize = 257;
+
+/// Wrap a package file in the decompressor its leading magic bytes call for.
+/// Any compression makepkg can produce is understood, as well as plain tar.
+pub(crate) fn decompress(package: &str, mut file: File) -> Result<Box<dyn Read>> {
+    let fetch = |e| PyxisError::fetch(package, e);
+    let mut magic = [0u8; TAR_MAGIC_OFFSET + 5];
+    let len = read_up_to(&mut file, &mut magic).map_err(fetch)?;
+    let magic = &magic[..len];
+    file.seek(SeekFrom::Start(0)).map_err(fetch)?;
+    if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Ok(Box::new(
+            zstd::stream::read::Decoder::new(file).map_err(fetch)?,
+        ))
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Ok(Box::new(xz::read::XzDecoder::new(file)))
+    } else if magic.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(flate2::read::MultiGzDecoder::new(file)))
+    } else if magic.starts_with(b"BZh") {
+        Ok(Box::new(bzip2::read::MultiBzDecoder::new(file)))
+    } else if magic.starts_with(&[0x04, 0x22, 0x4d, 0x18]) {
+        Ok(Box::new(lz4_flex::frame::FrameDecoder::new(file)))
+    } else if magic.starts_with(&[0x89, b'L', b'Z', b'O', 0x00, b'\r', b'\n', 0x1a, b'\n']) {
+        Ok(Box::new(Lzop::spawn(file).map_err(|e| {
+            PyxisError::fetch(package, format!("cannot run lzop: {}", e))
+        })?))
+    } else if magic[TAR_MAGIC_OFFSET.min(len)..].starts_with(b"ustar") {
+        Ok(Box::new(file))
+    } else {
+        Err(PyxisError::fetch(
+            package,
+            "unrecognized package format, expected a tar archive",
+        ))
+    }
+}
+
+/// Fill as much of `buf` as the file has, returning how much was read
+fn read_up_to(file: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+/// An lzop-compressed stream, decompressed by the `lzop` program since there
+/// is no Rust implementation of its container format
+struct Lzop {
+    child:  Child,
+    stdout: ChildStdout,
+}
+
+impl Lzop {
+    fn spawn(file: File) -> std::io::Result<Lzop> {
+        let mut child = Command::new("lzop")
+            .arg("-dc")
+            .stdin(file)
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().unwrap();
+        Ok(Lzop { child, stdout })
+    }
+}
+
+impl Read for Lzop {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            // Only a clean exit means the whole stream was decompressed
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("lzop failed: {}", status),
+                ));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for Lzop {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// The metadata a pacman package carries in its `.PKGINFO`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PkgInfo {
+    pub name:      String,
+    pub version:   String,
+    /// Dependency strings with their version constraints, e.g. `glibc>=2.35`
+    pub depends:   Vec<String>,
+    pub optdeps:   Vec<OptDep>,
+    pub relations: Relations,
+}
+
+impl PkgInfo {
+    fn parse(text: &str) -> PkgInfo {
+        let mut info = PkgInfo::default();
+        for line in text.lines() {
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) if !line.starts_with('#') => (key.trim(), value.trim()),
+                _ => continue,
+            };
+            match key {
+                "pkgname" => info.name = value.to_owned(),
+                "pkgver" => info.version = value.to_owned(),
+                "depend" => info.depends.push(value.to_owned()),
+                "optdepend" => info.optdeps.push(OptDep::parse(value)),
+                "provides" => info.relations.provides.push(value.to_owned()),
+                "conflict" => info.relations.conflicts.push(value.to_owned()),
+                "replaces" => info.relations.replaces.push(value.to_owned()),
+                _ => {}
+            }
+        }
+        info
+    }
+
+    /// Read the `.PKGINFO` of a package file. Results are cached, as the
+    /// same file is consulted for resolution, dependencies and version.
+    pub(crate) fn from_package(package: &str, path: &Path) -> Result<PkgInfo> {
+        if let Some(info) = PKGINFO_CACHE.lock().unwrap().get(path) {
+            return Ok(info.clone());
+        }
+        let err =
+            |e: std::io::Error| PyxisError::fetch(package, format!("{}: {}", path.display(), e));
+        let file = File::open(path).map_err(err)?;
+        let mut archive = tar::Archive::new(decompress(package, file)?);
+        for entry in archive.entries().map_err(err)? {
+            let mut entry = entry.map_err(err)?;
+            if entry.path().map_err(err)?.as_os_str() == ".PKGINFO" {
+                let mut text = String::new();
+                entry.read_to_string(&mut text).map_err(err)?;
+                let info = PkgInfo::parse(&text);
+                if info.name.is_empty() || info.version.is_empty() {
+                    return Err(PyxisError::fetch(
+                        package,
+                        format!("{}: .PKGINFO lacks pkgname or pkgver", path.display()),
+                    ));
+                }
+                PKGINFO_CACHE
+                    .lock()
+                    .unwrap()
+                    .insert(path.to_owned(), info.clone());
+                return Ok(info);
+            }
+        }
+        Err(PyxisError::fetch(
+            package,
+            format!("{}: not a pacman package, no .PKGINFO", path.display()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    /// A tar archive holding a single `.PKGINFO`
+    fn archive(pkginfo: &str) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(pkginfo.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, ".PKGINFO", pkginfo.as_bytes())
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn decompressed(data: &[u8]) -> Result<Vec<u8>> {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(data).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut out = Vec::new();
+        decompress("test", file)?.read_to_end(&mut out).unwrap();
+        Ok(out)
+    }
+
+    #[test]
+    fn pkginfo_parse() {
+        let info = PkgInfo::parse(
+            "# Generated by makepkg\n\
+             pkgname = python-matplotlib\n\
+             pkgver = 3.5.1-2\n\
+             pkgdesc = A plotting library = for python\n\
+             depend = python\n\
+             depend = freetype2>=2.10\n\
+             makedepend = python-setuptools\n",
+        );
+        assert_eq!(info.name, "python-matplotlib");
+        assert_eq!(info.version, "3.5.1-2");
+        assert_eq!(info.depends, vec!["python", "freetype2>=2.10"]);
+    }
+
+    #[test]
+    fn pkginfo_relations() {
+        let info = PkgInfo::parse(
+            "pkgname = iptables-nft\n\
+             pkgver = 1:1.8.8-1\n\
+             provides = iptables=1:1.8.8-1\n\
+             conflict = iptables\n\
+             replaces = iptables-legacy\n",
+        );
+        assert_eq!(info.relations.provides, vec!["iptables=1:1.8.8-1"]);
+        assert_eq!(info.relations.conflicts, vec!["iptables"]);
+        assert_eq!(info.relations.replaces, vec!["iptables-legacy"]);
+    }
+
+    #[test]
+    fn pkginfo_optdeps() {
+        let info = PkgInfo::parse(
+            "pkgname = python-matplotlib\n\
+             optdepend = tk: Tk backend\n\
+             optdepend = python-pyqt5\n",
+        );
+        let optdeps: Vec<_> = info.optdeps.iter().map(|o| o.dep.as_str()).collect();
+        assert_eq!(optdeps, vec!["tk", "python-pyqt5"]);
+        assert_eq!(info.optdeps[0].description.as_deref(), Some("Tk backend"));
+    }
+
+    #[test]
+    fn decompress_by_magic() {
+        let tar = archive("pkgname = test\n");
+        let compressed: Vec<(&str, Vec<u8>)> = vec![
+            ("tar", tar.clone()),
+            ("zstd", zstd::stream::encode_all(&tar[..], 0).unwrap()),
+            ("xz", {
+                let mut enc = xz::write::XzEncoder::new(Vec::new(), 6);
+                enc.write_all(&tar).unwrap();
+                enc.finish().unwrap()
+            }),
+            ("gzip", {
+                let mut enc =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                enc.write_all(&tar).unwrap();
+                enc.finish().unwrap()
+            }),
+            ("bzip2", {
+                let mut enc =
+                    bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+                enc.write_all(&tar).unwrap();
+                enc.finish().unwrap()
+            }),
+            ("lz4", {
+                let mut enc = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                enc.write_all(&tar).unwrap();
+                enc.finish().unwrap()
+            }),
+        ];
+        for (format, data) in compressed {
+            assert_eq!(decompressed(&data).unwrap(), tar, "{}", format);
+        }
+    }
+
+    #[test]
+    fn decompress_rejects_unknown_format() {
+        for data in [&b""[..], b"PK\x03\x04 not a package", &[0u8; 1024]] {
+            assert!(matches!(decompressed(data), Err(PyxisError::Fetch { .. })));
+        }
+    }
+}
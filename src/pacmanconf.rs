@@ -0,0 +1,313 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use alpm::SigLevel;
+
+use crate::{PyxisError, Result};
+
+/// The SigLevel pacman uses when pacman.conf does not set one, i.e.
+/// `Required DatabaseOptional`
+fn default_siglevel() -> SigLevel {
+    SigLevel::PACKAGE | SigLevel::DATABASE | SigLevel::DATABASE_OPTIONAL
+}
+
+/// A sync repository to register with alpm
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repository {
+    pub name:      String,
+    /// Server URLs with `$repo` and `$arch` already substituted
+    pub servers:   Vec<String>,
+    pub sig_level: SigLevel,
+}
+
+impl Repository {
+    /// A repository served from each mirror, in order. Mirrors are written
+    /// like pacman's `Server =` lines, with `$repo` and `$arch` substituted;
+    /// a mirror without `$repo` is taken to use the standard Arch layout.
+    pub fn from_mirrors(name: &str, mirrors: &[String]) -> Repository {
+        Repository {
+            name:      name.to_owned(),
+            servers:   mirrors
+                .iter()
+                .map(|m| {
+                    if m.contains("$repo") {
+                        expand_server(m, name, host_arch())
+                    } else {
+                        expand_server(&format!("{}/$repo/os/$arch", m), name, host_arch())
+                    }
+                })
+                .collect(),
+            sig_level: default_siglevel(),
+        }
+    }
+}
+
+fn host_arch() -> &'static str {
+    std::env::consts::ARCH
+}
+
+fn expand_server(server: &str, repo: &str, arch: &str) -> String {
+    server.replace("$repo", repo).replace("$arch", arch)
+}
+
+/// Apply the tokens of a pacman.conf `SigLevel` value on top of `base`.
+/// Tokens may be prefixed with `Package` or `Database` to only affect one of
+/// the two, as in pacman.
+fn parse_siglevel(base: SigLevel, value: &str) -> std::result::Result<SigLevel, String> {
+    let mut level = base;
+    for token in value.split_whitespace() {
+        let (targets, rest) = if let Some(rest) = token.strip_prefix("Package") {
+            ([true, false], rest)
+        } else if let Some(rest) = token.strip_prefix("Database") {
+            ([false, true], rest)
+        } else {
+            ([true, true], token)
+        };
+        for (i, _) in targets.iter().enumerate().filter(|(_, t)| **t) {
+            let (check, optional, marginal, unknown) = if i == 0 {
+                (
+                    SigLevel::PACKAGE,
+                    SigLevel::PACKAGE_OPTIONAL,
+                    SigLevel::PACKAGE_MARGINAL_OK,
+                    SigLevel::PACKAGE_UNKNOWN_OK,
+                )
+            } else {
+                (
+                    SigLevel::DATABASE,
+                    SigLevel::DATABASE_OPTIONAL,
+                    SigLevel::DATABASE_MARGINAL_OK,
+                    SigLevel::DATABASE_UNKNOWN_OK,
+                )
+            };
+            match rest {
+                "Never" => level.remove(check | optional),
+                "Optional" => level.insert(check | optional),
+                "Required" => {
+                    level.insert(check);
+                    level.remove(optional);
+                }
+                "TrustedOnly" => level.remove(marginal | unknown),
+                "TrustAll" => level.insert(marginal | unknown),
+                _ => return Err(format!("invalid SigLevel '{}'", token)),
+            }
+        }
+    }
+    Ok(level)
+}
+
+/// The parts of a pacman.conf that matter for resolving and fetching packages
+#[derive(Debug, Clone)]
+pub struct PacmanConf {
+    pub repos:     Vec<Repository>,
+    pub sig_level: SigLevel,
+}
+
+#[derive(Default)]
+struct Section {
+    name:      String,
+    servers:   Vec<String>,
+    sig_level: Option<String>,
+}
+
+#[derive(Default)]
+struct Parser {
+    arch:      Option<String>,
+    sig_level: Option<String>,
+    sections:  Vec<Section>,
+}
+
+impl PacmanConf {
+    /// Parse a pacman.conf, following `Include` directives
+    pub fn load(path: &Path) -> Result<PacmanConf> {
+        let mut parser = Parser::default();
+        let mut current = None;
+        parser.read(path, &mut current)?;
+        if let Some(section) = current {
+            parser.sections.push(section);
+        }
+
+        let conf_err = |reason: String| PyxisError::Config {
+            path: path.to_owned(),
+            reason,
+        };
+        let arch = match parser.arch.as_deref() {
+            None | Some("auto") => host_arch().to_owned(),
+            Some(arch) => arch.to_owned(),
+        };
+        let sig_level = match &parser.sig_level {
+            Some(value) => parse_siglevel(default_siglevel(), value).map_err(conf_err)?,
+            None => default_siglevel(),
+        };
+        let mut repos = Vec::new();
+        for section in parser.sections {
+            let repo_level = match &section.sig_level {
+                Some(value) => parse_siglevel(sig_level, value).map_err(conf_err)?,
+                None => sig_level,
+            };
+            repos.push(Repository {
+                servers:   section
+                    .servers
+                    .iter()
+                    .map(|s| expand_server(s, &section.name, &arch))
+                    .collect(),
+                name:      section.name,
+                sig_level: repo_level,
+            });
+        }
+        Ok(PacmanConf { repos, sig_level })
+    }
+}
+
+impl Parser {
+    /// Read one file into the parser. `current` is the repository section
+    /// being filled, or `None` while in `[options]`; included files continue
+    /// the section they were included from.
+    fn read(&mut self, path: &Path, current: &mut Option<Section>) -> Result<()> {
+        let conf_err = |reason: String| PyxisError::Config {
+            path: path.to_owned(),
+            reason,
+        };
+        let file = File::open(path).map_err(|e| conf_err(e.to_string()))?;
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| conf_err(e.to_string()))?;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                if let Some(section) = current.take() {
+                    self.sections.push(section);
+                }
+                if name != "options" {
+                    *current = Some(Section {
+                        name: name.to_owned(),
+                        ..Section::default()
+                    });
+                }
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => (line, ""),
+            };
+            match (key, current.as_mut()) {
+                ("Include", _) => {
+                    let pattern = path.parent().unwrap_or_else(|| Path::new("/")).join(value);
+                    let paths = glob::glob(&pattern.to_string_lossy())
+                        .map_err(|e| conf_err(format!("bad Include '{}': {}", value, e)))?;
+                    for include in paths {
+                        let include: PathBuf = include.map_err(|e| conf_err(e.to_string()))?;
+                        self.read(&include, current)?;
+                    }
+                }
+                ("Server", Some(section)) => section.servers.push(value.to_owned()),
+                ("SigLevel", Some(section)) => section.sig_level = Some(value.to_owned()),
+                ("SigLevel", None) => self.sig_level = Some(value.to_owned()),
+                ("Architecture", None) => {
+                    self.arch = value.split_whitespace().next().map(str::to_owned)
+                }
+                // Everything else only matters to pacman itself
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn siglevel_without_prefix_affects_both() {
+        let level = parse_siglevel(default_siglevel(), "Never").unwrap();
+        assert!(!level.intersects(SigLevel::PACKAGE | SigLevel::DATABASE));
+        assert!(!level.intersects(SigLevel::PACKAGE_OPTIONAL | SigLevel::DATABASE_OPTIONAL));
+    }
+
+    #[test]
+    fn siglevel_prefixes_affect_one() {
+        let level = parse_siglevel(
+            SigLevel::empty(),
+            "PackageRequired DatabaseOptional PackageTrustAll",
+        )
+        .unwrap();
+        assert_eq!(
+            level,
+            SigLevel::PACKAGE
+                | SigLevel::PACKAGE_MARGINAL_OK
+                | SigLevel::PACKAGE_UNKNOWN_OK
+                | SigLevel::DATABASE
+                | SigLevel::DATABASE_OPTIONAL
+        );
+    }
+
+    #[test]
+    fn siglevel_applies_on_top_of_base() {
+        let level = parse_siglevel(default_siglevel(), "DatabaseNever").unwrap();
+        assert_eq!(level, SigLevel::PACKAGE);
+    }
+
+    #[test]
+    fn siglevel_rejects_unknown_token() {
+        assert!(parse_siglevel(default_siglevel(), "PackageSometimes").is_err());
+    }
+
+    #[test]
+    fn include_continues_section() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("pacman.conf"),
+            "[options]\nArchitecture = x86_64\nSigLevel = Never\n\n\
+             [core]\nInclude = mirrorlist\n\n\
+             [extra]\nSigLevel = DatabaseRequired\nServer = https://example.org/$repo\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("mirrorlist"),
+            "# mirrors\nServer = https://a.example.org/$repo/os/$arch\n\
+             Server = https://b.example.org/$repo/os/$arch\n",
+        )
+        .unwrap();
+
+        let conf = PacmanConf::load(&dir.path().join("pacman.conf")).unwrap();
+        assert_eq!(conf.sig_level, SigLevel::empty());
+        assert_eq!(
+            conf.repos,
+            vec![
+                Repository {
+                    name:      "core".to_owned(),
+                    servers:   vec![
+                        "https://a.example.org/core/os/x86_64".to_owned(),
+                        "https://b.example.org/core/os/x86_64".to_owned(),
+                    ],
+                    sig_level: SigLevel::empty(),
+                },
+                Repository {
+                    name:      "extra".to_owned(),
+                    servers:   vec!["https://example.org/extra".to_owned()],
+                    sig_level: SigLevel::DATABASE,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_include_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("pacman.conf"),
+            "[core]\nInclude = mirrorlist.d/*\nServer = https://example.org/$repo\n",
+        )
+        .unwrap();
+
+        let conf = PacmanConf::load(&dir.path().join("pacman.conf")).unwrap();
+        assert_eq!(conf.repos.len(), 1);
+        assert_eq!(conf.repos[0].servers, vec!["https://example.org/core"]);
+    }
+}
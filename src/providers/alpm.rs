@@ -6,165 +6,560 @@ use std::{
     sync::Mutex,
 };
 
+use alpm::SigLevel;
 use itertools::Itertools;
 use lazy_static::lazy_static;
-use pyxis_parcel::{InodeAttr, InodeKind, ParcelHandle, ReaderWriter};
+use log::{debug, info, warn};
+use pyxis_parcel::{InodeAttr, InodeKind, ParcelHandle};
+use sha2::{Digest, Sha256};
 
-use crate::{exists_parcel, get_parcel_path, ParcelProvider};
+use super::Provider;
+use crate::{
+    download::download_mirrored,
+    get_parcel_path,
+    package::{decompress, PkgInfo},
+    pacmanconf::Repository,
+    parcel_current,
+    spec::parse_dep,
+    store::{persist, temp_beside, write_parcel},
+    Config, OptDep, PackageId, PackageSpec, PyxisError, Relations, Result, VersionConstraint,
+};
+
+pub const PREFIX: &str = "arch";
+
+/// The alpm handle and the repositories registered with it
+struct AlpmState {
+    /// The settings the handle was opened with, to notice when a different
+    /// configuration needs a new one
+    source: (
+        Option<PathBuf>,
+        PathBuf,
+        Option<PathBuf>,
+        Vec<String>,
+        Vec<String>,
+        PathBuf,
+    ),
+    /// The database path the handle was opened on
+    db_dir: PathBuf,
+    repos:  Vec<Repository>,
+    alpm:   alpm::Alpm,
+}
 
 lazy_static! {
-    static ref ALPM_MUTEX: Mutex<Option<alpm::Alpm>> = Mutex::new(None);
+    static ref ALPM_MUTEX: Mutex<Option<AlpmState>> = Mutex::new(None);
+}
+
+pub struct AlpmProvider;
+
+impl Provider for AlpmProvider {
+    fn prefix(&self) -> &str {
+        PREFIX
+    }
+
+    fn resolve(
+        &self,
+        config: &Config,
+        package: &str,
+        constraint: Option<&VersionConstraint>,
+    ) -> Result<String> {
+        if let Some(info) = override_info(config, package)? {
+            return match constraint {
+                Some(constraint) if !constraint.satisfied_by(&info.version) => {
+                    Err(PyxisError::Resolution(format!(
+                        "override for {} has version {}, which does not satisfy {}",
+                        package, info.version, constraint
+                    )))
+                }
+                _ => Ok(package.to_owned()),
+            };
+        }
+        let constraint = match constraint {
+            None => return alpm_find_satisfier(config, package),
+            Some(constraint) => constraint,
+        };
+        alpm_find_satisfier(config, &format!("{}{}", package, constraint)).map_err(|e| {
+            // Say which version is available, if any, to tell a version
+            // mismatch apart from a missing package
+            match alpm_find_satisfier(config, package)
+                .and_then(|name| alpm_get_version(config, &name).map(|v| (name, v)))
+            {
+                Ok((name, version)) => PyxisError::Resolution(format!(
+                    "no package satisfies '{}{}': {} {} is available",
+                    package, constraint, name, version
+                )),
+                Err(_) => e,
+            }
+        })
+    }
+
+    fn get_deps(&self, config: &Config, package: &str) -> Result<Vec<PackageSpec>> {
+        get_deps(config, package)?
+            .iter()
+            .map(|x| {
+                let (name, constraint) = parse_dep(x)?;
+                Ok(PackageSpec::new(PREFIX, &name, constraint))
+            })
+            .collect()
+    }
+
+    fn get_version(&self, config: &Config, package: &str) -> Result<String> {
+        alpm_get_version(config, package)
+    }
+
+    fn relations(&self, config: &Config, package: &str) -> Result<Relations> {
+        alpm_relations(config, package)
+    }
+
+    fn optdeps(&self, config: &Config, package: &str) -> Result<Vec<OptDep>> {
+        alpm_optdeps(config, package)
+    }
+
+    fn group_members(&self, config: &Config, group: &str) -> Result<Vec<String>> {
+        alpm_group_members(config, group)
+    }
+
+    fn source(&self, config: &Config, package: &str) -> Result<Option<String>> {
+        config
+            .overrides
+            .get(package)
+            .map(|path| override_source(package, path))
+            .transpose()
+    }
+
+    fn parcel_build(&self, config: &Config, package: &str) -> Result<()> {
+        parcel_build(config, package)
+    }
 }
 
-pub fn with_alpm(f: Box<dyn FnOnce(&alpm::Alpm) -> Vec<String>>) -> Vec<String> {
+fn with_state<T, F: FnOnce(&AlpmState) -> T>(config: &Config, f: F) -> Result<T> {
     let mut mres = ALPM_MUTEX.lock().unwrap();
-    if mres.is_none() {
-        *mres = Some(alpm::Alpm::new("/", "/var/lib/pacman").unwrap());
-        mres.as_ref()
-            .unwrap()
-            .register_syncdb("core", alpm::SigLevel::USE_DEFAULT)
-            .unwrap();
-        mres.as_ref()
-            .unwrap()
-            .register_syncdb("extra", alpm::SigLevel::USE_DEFAULT)
-            .unwrap();
-        mres.as_ref()
-            .unwrap()
-            .register_syncdb("community", alpm::SigLevel::USE_DEFAULT)
-            .unwrap();
+    let source = (
+        config.db_path.clone(),
+        config.parcel_root.clone(),
+        config.pacman_conf.clone(),
+        config.repos.clone(),
+        config.mirrors.clone(),
+        config.gpg_dir.clone(),
+    );
+    if mres.as_ref().map(|s| &s.source) != Some(&source) {
+        *mres = None;
+        let repos = config.repositories()?;
+        let db_dir = config.db_dir()?;
+        for repo in &repos {
+            if !db_dir
+                .join("sync")
+                .join(format!("{}.db", repo.name))
+                .exists()
+            {
+                return Err(PyxisError::Resolution(format!(
+                    "repository {} has not been synced into {}; run `pyxis sync`, naming \
+                     the manifest if it sets !pacman-conf",
+                    repo.name,
+                    db_dir.display()
+                )));
+            }
+        }
+        let db_path = db_dir.to_string_lossy();
+        let alpm = alpm::Alpm::new("/", db_path.as_ref()).map_err(|e| {
+            PyxisError::Resolution(format!("cannot open package database {}: {}", db_path, e))
+        })?;
+        // Registering a repository checks its database signature
+        alpm.set_gpgdir(config.gpg_dir.to_string_lossy().as_ref())
+            .map_err(|e| {
+                PyxisError::Resolution(format!(
+                    "cannot use keyring {}: {}",
+                    config.gpg_dir.display(),
+                    e
+                ))
+            })?;
+
+        for repo in &repos {
+            alpm.register_syncdb(repo.name.as_str(), repo.sig_level)
+                .map_err(|e| {
+                    PyxisError::Resolution(format!(
+                        "cannot register repository {}: {}",
+                        repo.name, e
+                    ))
+                })?;
+        }
+        *mres = Some(AlpmState {
+            source,
+            db_dir,
+            repos,
+            alpm,
+        });
     }
-    f(mres.as_ref().unwrap())
+    Ok(f(mres.as_ref().unwrap()))
 }
 
-pub fn alpm_find_satisfier(package: &str) -> Vec<String> {
-    let pkb = Box::new(package.to_owned());
-    with_alpm(Box::new(|alpm: &alpm::Alpm| {
-        let mut res = Vec::new();
-        let package = *pkb;
-        res.push(
-            alpm.syncdbs()
-                .find_satisfier(package)
-                .unwrap()
-                .name()
-                .to_owned(),
-        );
-        res
-    }))
+/// Drop the alpm handle, so that the next use reopens the sync databases
+pub(crate) fn reset() {
+    *ALPM_MUTEX.lock().unwrap() = None;
+}
+
+pub fn with_alpm<T, F: FnOnce(&alpm::Alpm) -> T>(config: &Config, f: F) -> Result<T> {
+    with_state(config, |state| f(&state.alpm))
+}
+
+/// Look up a registered repository by name
+fn repository(config: &Config, name: &str) -> Result<Repository> {
+    with_state(config, |state| {
+        state.repos.iter().find(|r| r.name == name).cloned()
+    })?
+    .ok_or_else(|| PyxisError::Resolution(format!("unknown repository '{}'", name)))
+}
+
+/// The `.PKGINFO` of the local package file overriding `package`, if any
+fn override_info(config: &Config, package: &str) -> Result<Option<PkgInfo>> {
+    config
+        .overrides
+        .get(package)
+        .map(|path| PkgInfo::from_package(package, path))
+        .transpose()
+}
+
+/// Name an override by its path and contents, so that replacing the file
+/// rebuilds the parcel even when the version stays the same
+fn override_source(package: &str, path: &Path) -> Result<String> {
+    let err = |e: std::io::Error| PyxisError::fetch(package, format!("{}: {}", path.display(), e));
+    let path = path.canonicalize().map_err(err)?;
+    let mut file = File::open(&path).map_err(err)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).map_err(err)?;
+    Ok(format!(
+        "{} (sha256 {:x})",
+        path.display(),
+        hasher.finalize()
+    ))
+}
+
+pub fn alpm_find_satisfier(config: &Config, package: &str) -> Result<String> {
+    with_alpm(config, |alpm: &alpm::Alpm| {
+        alpm.syncdbs()
+            .find_satisfier(package)
+            .map(|pkg| pkg.name().to_owned())
+    })?
+    .ok_or_else(|| match alpm_group_members(config, package) {
+        Ok(_) => PyxisError::Resolution(format!(
+            "'{}' is a package group, not a package; write it as @{}",
+            package, package
+        )),
+        Err(_) => PyxisError::Resolution(format!("no package satisfies '{}'", package)),
+    })
+}
+
+/// What the sync database says about a package file
+pub struct SyncEntry {
+    pub repo:      String,
+    pub filename:  String,
+    pub sha256:    Option<String>,
+    /// Base64 signature, when the database carries it
+    pub signature: Option<String>,
+}
+
+/// The first sync database that has a package, and the package in it. As with
+/// pacman, a repository listed earlier shadows any later one.
+fn sync_pkg<'a>(alpm: &'a alpm::Alpm, package: &str) -> Option<(alpm::Db<'a>, alpm::Package<'a>)> {
+    alpm.syncdbs()
+        .into_iter()
+        .find_map(|db| db.pkg(package).ok().map(|pkg| (db, pkg)))
+}
+
+pub fn alpm_resolve_package(config: &Config, package: &str) -> Result<SyncEntry> {
+    with_alpm(config, |alpm: &alpm::Alpm| {
+        sync_pkg(alpm, package).map(|(db, pkg)| SyncEntry {
+            repo:      db.name().to_owned(),
+            filename:  pkg.filename().to_owned(),
+            sha256:    pkg.sha256sum().map(str::to_owned),
+            signature: pkg.base64_sig().map(str::to_owned),
+        })
+    })?
+    .ok_or_else(|| PyxisError::Resolution(format!("package '{}' not found", package)))
+}
+
+/// The dependency strings of a package, with their version constraints, e.g.
+/// `glibc>=2.35`
+pub fn get_deps(config: &Config, package: &str) -> Result<Vec<String>> {
+    if let Some(info) = override_info(config, package)? {
+        return Ok(info.depends);
+    }
+    let deps = with_alpm(config, |alpm: &alpm::Alpm| match sync_pkg(alpm, package) {
+        Some((_, pkg)) => pkg.depends().iter().map(|x| x.to_string()).collect(),
+        None => Vec::new(),
+    })?;
+    Ok(deps.into_iter().unique().collect())
 }
 
-pub fn alpm_resolve_package(package: &str) -> Vec<String> {
-    let pkb = Box::new(package.to_owned());
-    with_alpm(Box::new(|alpm: &alpm::Alpm| {
+/// The members of a group across every sync database, in database order
+pub fn alpm_group_members(config: &Config, group: &str) -> Result<Vec<String>> {
+    let members = with_alpm(config, |alpm: &alpm::Alpm| {
         let mut res = Vec::new();
-        let package = *pkb;
         for db in alpm.syncdbs() {
-            if let Ok(pkg) = db.pkg(package.clone()) {
-                res = vec![db.name().to_owned(), pkg.filename().to_owned()];
+            if let Ok(group) = db.group(group) {
+                res.extend(group.packages().iter().map(|pkg| pkg.name().to_owned()));
             }
         }
         res
-    }))
+    })?;
+    if members.is_empty() {
+        return Err(PyxisError::Resolution(format!("no group '{}'", group)));
+    }
+    Ok(members.into_iter().unique().collect())
 }
 
-pub fn get_deps(package: &str) -> Vec<String> {
-    let pkb = Box::new(package.to_owned());
-    with_alpm(Box::new(|alpm: &alpm::Alpm| {
-        let mut res = Vec::new();
-        let package = *pkb;
-        for db in alpm.syncdbs() {
-            if let Ok(pkg) = db.pkg(package.clone()) {
-                res = pkg.depends().iter().map(|x| x.name().to_owned()).collect();
+pub fn alpm_optdeps(config: &Config, package: &str) -> Result<Vec<OptDep>> {
+    if let Some(info) = override_info(config, package)? {
+        return Ok(info.optdeps);
+    }
+    with_alpm(config, |alpm: &alpm::Alpm| {
+        sync_pkg(alpm, package).map(|(_, pkg)| {
+            pkg.optdepends()
+                .iter()
+                .map(|x| OptDep::parse(&x.to_string()))
+                .collect()
+        })
+    })?
+    .ok_or_else(|| PyxisError::Resolution(format!("package '{}' not found", package)))
+}
+
+pub fn alpm_relations(config: &Config, package: &str) -> Result<Relations> {
+    if let Some(info) = override_info(config, package)? {
+        return Ok(info.relations);
+    }
+    with_alpm(config, |alpm: &alpm::Alpm| {
+        sync_pkg(alpm, package).map(|(_, pkg)| {
+            let strings =
+                |deps: alpm::AlpmList<alpm::Dep>| deps.iter().map(|x| x.to_string()).collect();
+            Relations {
+                provides:  strings(pkg.provides()),
+                conflicts: strings(pkg.conflicts()),
+                replaces:  strings(pkg.replaces()),
             }
+        })
+    })?
+    .ok_or_else(|| PyxisError::Resolution(format!("package '{}' not found", package)))
+}
+
+pub fn alpm_get_version(config: &Config, package: &str) -> Result<String> {
+    if let Some(info) = override_info(config, package)? {
+        return Ok(info.version);
+    }
+    with_alpm(config, |alpm: &alpm::Alpm| {
+        sync_pkg(alpm, package).map(|(_, pkg)| pkg.version().as_str().to_owned())
+    })?
+    .ok_or_else(|| PyxisError::Resolution(format!("package '{}' not found", package)))
+}
+
+pub fn alpm_fetch(config: &Config, package: &str) -> Result<File> {
+    info!("Fetching {}", package);
+    let fetch = |e| PyxisError::fetch(package, e);
+    if let Some(path) = config.overrides.get(package) {
+        debug!("Using override {}", path.display());
+        let file = File::open(path)
+            .map_err(|e| PyxisError::fetch(package, format!("{}: {}", path.display(), e)))?;
+        return Ok(file);
+    }
+
+    let entry = alpm_resolve_package(config, package)?;
+    let filename = &entry.filename;
+
+    let repo = repository(config, &entry.repo)?;
+    let path = config.cache_dir().join(filename);
+    if !in_cache(package, &entry, &path)? {
+        let mut tmp = temp_beside(&path)?;
+        fill_cache(config, package, &entry, &repo, tmp.as_file_mut(), &path)?;
+        persist(tmp, &path)?;
+    }
+    verify_signature(config, package, &entry, &repo, &path)?;
+
+    let file = File::open(&path).map_err(fetch)?;
+    Ok(file)
+}
+
+/// Whether the package cache in the store holds a good copy of the package.
+/// A copy that fails its checksum is removed.
+fn in_cache(package: &str, entry: &SyncEntry, path: &Path) -> Result<bool> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(PyxisError::fetch(package, e)),
+    };
+    match verify_checksum(package, entry, &mut file) {
+        Ok(()) => {
+            debug!("Using cached {}", path.display());
+            Ok(true)
         }
-        res
-    }))
-    .iter()
-    .map(|x| alpm_find_satisfier(x)[0].clone())
-    .unique()
-    .collect()
+        Err(e) => {
+            warn!("Discarding cached {}: {}", path.display(), e);
+            std::fs::remove_file(path).map_err(|e| PyxisError::fetch(package, e))?;
+            Ok(false)
+        }
+    }
 }
 
-pub fn alpm_get_version(package: &str) -> Vec<String> {
-    let pkb = Box::new(package.to_owned());
-    with_alpm(Box::new(|alpm: &alpm::Alpm| {
-        let mut res = Vec::new();
-        let package = *pkb;
-        for db in alpm.syncdbs() {
-            if let Ok(pkg) = db.pkg(package.clone()) {
-                res = vec![pkg.version().as_str().to_owned()];
+/// The files in the store's package cache that building `package` uses: the
+/// package file and its detached signature. Overridden packages use none.
+pub(crate) fn cache_files(config: &Config, package: &str) -> Result<Vec<String>> {
+    if config.overrides.contains_key(package) {
+        return Ok(Vec::new());
+    }
+    let entry = alpm_resolve_package(config, package)?;
+    Ok(vec![format!("{}.sig", entry.filename), entry.filename])
+}
+
+/// Fill `out` with the package, copied from one of the extra cache
+/// directories if any has a good copy and downloaded otherwise. A signature
+/// kept beside the copied package is brought along to `path`.sig, so that
+/// checking it needs no mirror either.
+fn fill_cache(
+    config: &Config,
+    package: &str,
+    entry: &SyncEntry,
+    repo: &Repository,
+    out: &mut File,
+    path: &Path,
+) -> Result<()> {
+    let fetch = |e| PyxisError::fetch(package, e);
+    for dir in &config.cache_dirs {
+        let candidate = dir.join(&entry.filename);
+        let mut src = match File::open(&candidate) {
+            Ok(src) => src,
+            Err(_) => continue,
+        };
+        out.set_len(0).map_err(fetch)?;
+        out.seek(SeekFrom::Start(0)).map_err(fetch)?;
+        std::io::copy(&mut src, out).map_err(fetch)?;
+        out.seek(SeekFrom::Start(0)).map_err(fetch)?;
+        match verify_checksum(package, entry, out) {
+            Ok(()) => {
+                debug!("Copied {}", candidate.display());
+                return copy_signature(package, &candidate, path);
             }
+            Err(e) => warn!("Ignoring {}: {}", candidate.display(), e),
         }
-        res
-    }))
+    }
+    let url = download_mirrored(config, package, &repo.servers, &entry.filename, out)?;
+    debug!("Downloaded {} from {}", entry.filename, url);
+    verify_checksum(package, entry, out)
 }
 
-pub fn alpm_fetch(package: &str) -> (File, String) {
-    println!("Fetching {}", package);
-    match package {
-        "linux" => {
-            let file =
-                File::open("/home/asent/build/linux/linux-5.16-1-x86_64.pkg.tar.zst").unwrap();
-            (file, String::from("zst"))
-        }
-        "linux-docs" => {
-            let file =
-                File::open("/home/asent/build/linux/linux-docs-5.16-1-x86_64.pkg.tar.zst").unwrap();
-            (file, String::from("zst"))
-        }
-        "linux-headers" => {
-            let file =
-                File::open("/home/asent/build/linux/linux-headers-5.16-1-x86_64.pkg.tar.zst")
-                    .unwrap();
-            (file, String::from("zst"))
+/// Copy the `.sig` file beside `from`, if there is one, to beside `to`
+fn copy_signature(package: &str, from: &Path, to: &Path) -> Result<()> {
+    let fetch = |e| PyxisError::fetch(package, e);
+    let sig = |path: &Path| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".sig");
+        PathBuf::from(name)
+    };
+    let (from, to) = (sig(from), sig(to));
+    let mut src = match File::open(&from) {
+        Ok(src) => src,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(fetch(e)),
+    };
+    let mut tmp = temp_beside(&to)?;
+    std::io::copy(&mut src, tmp.as_file_mut()).map_err(fetch)?;
+    debug!("Copied {}", from.display());
+    persist(tmp, &to)
+}
+
+/// Check a downloaded package against the SHA256 in the sync database
+fn verify_checksum(package: &str, entry: &SyncEntry, file: &mut File) -> Result<()> {
+    let fetch = |e| PyxisError::fetch(package, e);
+    let expected = entry.sha256.as_deref().ok_or_else(|| {
+        PyxisError::fetch(
+            package,
+            format!("repository {} lists no checksum for it", entry.repo),
+        )
+    })?;
+    let mut hasher = Sha256::new();
+    std::io::copy(file, &mut hasher).map_err(fetch)?;
+    file.seek(SeekFrom::Start(0)).map_err(fetch)?;
+    let actual = format!("{:x}", hasher.finalize());
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(PyxisError::fetch(
+            package,
+            format!(
+                "checksum mismatch for {}: expected {}, got {}",
+                entry.filename, expected, actual
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Check the signature of a downloaded package as the repository's SigLevel
+/// asks. The signature comes from the sync database, or else from a `.sig`
+/// file next to the package on the mirror.
+fn verify_signature(
+    config: &Config,
+    package: &str,
+    entry: &SyncEntry,
+    repo: &Repository,
+    path: &Path,
+) -> Result<()> {
+    if !repo.sig_level.contains(SigLevel::PACKAGE) {
+        return Ok(());
+    }
+    let fetch = |e| PyxisError::fetch(package, e);
+    let sig_name = format!("{}.sig", entry.filename);
+    let sig_path = path.with_file_name(&sig_name);
+    match &entry.signature {
+        Some(signature) => {
+            let signature = alpm::decode_signature(signature.as_str()).map_err(|e| {
+                PyxisError::fetch(package, format!("bad signature in sync database: {}", e))
+            })?;
+            let tmp = temp_beside(&sig_path)?;
+            tmp.as_file().write_all(&signature).map_err(fetch)?;
+            persist(tmp, &sig_path)?;
         }
-        _ => {
-            let pkg = alpm_resolve_package(package);
-            let repo = &pkg[0];
-            let filename = &pkg[1];
-
-            println!("{}", filename);
-
-            let mut easy = curl::easy::Easy::new();
-            easy.url(&format!(
-                "http://archrepo.calamityconductor.com/{}/os/x86_64/{}",
-                repo, filename
-            ))
-            .unwrap();
-            easy.follow_location(true).unwrap();
-
-            let mut file = tempfile::tempfile().unwrap();
-
-            let mut transfer = easy.transfer();
-            transfer
-                .write_function(|data| {
-                    file.write_all(data).unwrap();
-                    Ok(data.len())
-                })
-                .unwrap();
-            transfer.perform().unwrap();
-            std::mem::drop(transfer);
-
-            file.seek(SeekFrom::Start(0)).unwrap();
-            (file, filename.split('.').next_back().unwrap().to_owned())
+        // Kept in the cache beside the package from an earlier download
+        None if sig_path.exists() => {}
+        None => {
+            let mut tmp = temp_beside(&sig_path)?;
+            match download_mirrored(config, package, &repo.servers, &sig_name, tmp.as_file_mut()) {
+                Ok(_) => persist(tmp, &sig_path)?,
+                // libalpm accepts a missing signature when it is optional
+                Err(_) if repo.sig_level.contains(SigLevel::PACKAGE_OPTIONAL) => {}
+                Err(e) => return Err(e),
+            }
         }
     }
+    // Checking a signature reads the whole package, so use a handle of our
+    // own rather than holding up resolution on the shared one
+    let verify_err =
+        |e| PyxisError::fetch(package, format!("signature verification failed: {}", e));
+    let db_path = with_state(config, |state| state.db_dir.clone())?;
+    let alpm = alpm::Alpm::new("/", db_path.to_string_lossy().as_ref()).map_err(verify_err)?;
+    alpm.set_gpgdir(config.gpg_dir.to_string_lossy().as_ref())
+        .map_err(verify_err)?;
+    alpm.pkg_load(path.to_string_lossy().as_ref(), true, repo.sig_level)
+        .map(|_| ())
+        .map_err(verify_err)
 }
 
 fn parcel_from_pacman<R: Sized + std::io::Read>(
-    provider: ParcelProvider,
+    config: &Config,
     package: &str,
     mut archive: tar::Archive<R>,
-) {
+) -> Result<()> {
+    let parcelpath = get_parcel_path(config, PREFIX, package);
+    let pio = |e| PyxisError::parcel_io(&parcelpath, e);
+    let tio = |e: std::io::Error| PyxisError::parcel_io(&parcelpath, e);
+    let bad = |what: &str| {
+        PyxisError::parcel_io(&parcelpath, format!("malformed package archive: {}", what))
+    };
+
     let mut dir_map: BTreeMap<PathBuf, u64> = BTreeMap::new();
     dir_map.insert(PathBuf::from("/"), 1);
 
     let mut parcel = ParcelHandle::new();
 
-    parcel.metadata().depends = get_deps(package)
+    parcel.metadata().depends = get_deps(config, package)?
         .iter()
-        .map(|x| String::from("arch|") + x)
+        .map(|x| PackageId::new(PREFIX, x).to_string())
         .collect();
-    parcel.metadata().version = alpm_get_version(package)[0].clone();
+    parcel.metadata().version = alpm_get_version(config, package)?;
 
     let time = std::time::SystemTime::now();
     let attr = InodeAttr {
@@ -187,15 +582,15 @@ fn parcel_from_pacman<R: Sized + std::io::Read>(
             pyxis_dir,
             InodeKind::Directory,
         )
-        .unwrap();
+        .map_err(pio)?;
     parcel
         .insert_dirent(
             pyxis_dir,
-            std::ffi::OsString::from("arch"),
+            std::ffi::OsString::from(PREFIX),
             provider_dir,
             InodeKind::Directory,
         )
-        .unwrap();
+        .map_err(pio)?;
     parcel
         .insert_dirent(
             provider_dir,
@@ -203,154 +598,124 @@ fn parcel_from_pacman<R: Sized + std::io::Read>(
             parcel_dir,
             InodeKind::Directory,
         )
-        .unwrap();
+        .map_err(pio)?;
 
-    for ent in archive.entries().unwrap() {
-        let mut e = ent.unwrap();
+    for ent in archive.entries().map_err(tio)? {
+        let mut e = ent.map_err(tio)?;
         let ent_header = e.header().clone();
 
-        let time =
-            std::time::UNIX_EPOCH + std::time::Duration::from_secs(ent_header.mtime().unwrap());
+        let time = std::time::UNIX_EPOCH
+            + std::time::Duration::from_secs(ent_header.mtime().map_err(tio)?);
 
-        let header = ent_header.as_ustar().unwrap();
-        let p = e.path().unwrap();
-        let p_st = p.to_str().unwrap();
+        let header = ent_header
+            .as_ustar()
+            .ok_or_else(|| bad("not a ustar entry"))?;
+        let p = e.path().map_err(tio)?;
+        let p_st = p.to_str().ok_or_else(|| bad("non-UTF-8 path"))?;
         let entry_path = Path::new("/").join(p_st);
-        let parent_inode = *dir_map.get(entry_path.parent().unwrap()).unwrap();
-        let entry_name = entry_path.file_name().unwrap();
+        let parent_inode = *entry_path
+            .parent()
+            .and_then(|parent| dir_map.get(parent))
+            .ok_or_else(|| bad("entry outside of a known directory"))?;
+        let entry_name = entry_path
+            .file_name()
+            .ok_or_else(|| bad("entry without a name"))?
+            .to_owned();
         match header.typeflag {
             [b'0'] => {
                 let attr = InodeAttr {
                     atime: time,
                     ctime: time,
                     mtime: time,
-                    uid:   ent_header.uid().unwrap() as u32,
-                    gid:   ent_header.gid().unwrap() as u32,
+                    uid:   ent_header.uid().map_err(tio)? as u32,
+                    gid:   ent_header.gid().map_err(tio)? as u32,
                     nlink: 1,
-                    perm:  ent_header.mode().unwrap(),
+                    perm:  ent_header.mode().map_err(tio)?,
                     rdev:  0,
                 };
                 let mut buf = Vec::new();
-                e.read_to_end(&mut buf).unwrap();
+                e.read_to_end(&mut buf).map_err(tio)?;
                 let ino = parcel
                     .add_file(pyxis_parcel::FileAdd::Bytes(buf), attr, BTreeMap::new())
-                    .unwrap();
-                match (parent_inode, entry_name.to_str().unwrap()) {
-                    (1, ".INSTALL" | ".BUILDINFO" | ".MTREE" | ".PKGINFO") => parcel
-                        .insert_dirent(
-                            parcel_dir,
-                            entry_name.to_owned(),
-                            ino,
-                            InodeKind::RegularFile,
-                        )
-                        .unwrap(),
+                    .map_err(pio)?;
+                match (parent_inode, entry_name.to_str()) {
+                    (1, Some(".INSTALL" | ".BUILDINFO" | ".MTREE" | ".PKGINFO")) => parcel
+                        .insert_dirent(parcel_dir, entry_name, ino, InodeKind::RegularFile)
+                        .map_err(pio)?,
                     _ => parcel
-                        .insert_dirent(
-                            parent_inode,
-                            entry_name.to_owned(),
-                            ino,
-                            InodeKind::RegularFile,
-                        )
-                        .unwrap(),
+                        .insert_dirent(parent_inode, entry_name, ino, InodeKind::RegularFile)
+                        .map_err(pio)?,
                 }
             }
             [b'1'] => {
                 let link_name = ent_header
                     .link_name()
-                    .unwrap()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_owned()
-                    .into();
-                let ino = parcel.add_hardlink(link_name).unwrap();
+                    .map_err(tio)?
+                    .ok_or_else(|| bad("hardlink without target"))?
+                    .into_owned()
+                    .into_os_string();
+                let ino = parcel.add_hardlink(link_name).map_err(pio)?;
                 parcel
-                    .insert_dirent(
-                        parent_inode,
-                        entry_name.to_owned(),
-                        ino,
-                        InodeKind::RegularFile,
-                    )
-                    .unwrap();
+                    .insert_dirent(parent_inode, entry_name, ino, InodeKind::RegularFile)
+                    .map_err(pio)?;
             }
             [b'2'] => {
                 let link_name = ent_header
                     .link_name()
-                    .unwrap()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_owned()
-                    .into();
+                    .map_err(tio)?
+                    .ok_or_else(|| bad("symlink without target"))?
+                    .into_owned()
+                    .into_os_string();
                 let attr = pyxis_parcel::InodeAttr {
                     atime: time,
                     ctime: time,
                     mtime: time,
-                    uid:   ent_header.uid().unwrap() as u32,
-                    gid:   ent_header.gid().unwrap() as u32,
+                    uid:   ent_header.uid().map_err(tio)? as u32,
+                    gid:   ent_header.gid().map_err(tio)? as u32,
                     nlink: 1,
-                    perm:  ent_header.mode().unwrap(),
+                    perm:  ent_header.mode().map_err(tio)?,
                     rdev:  0,
                 };
                 let ino = parcel
                     .add_symlink(link_name, attr, BTreeMap::new())
-                    .unwrap();
+                    .map_err(pio)?;
                 parcel
-                    .insert_dirent(parent_inode, entry_name.to_owned(), ino, InodeKind::Symlink)
-                    .unwrap();
+                    .insert_dirent(parent_inode, entry_name, ino, InodeKind::Symlink)
+                    .map_err(pio)?;
             }
             [b'5'] => {
                 let attr = pyxis_parcel::InodeAttr {
                     atime: time,
                     ctime: time,
                     mtime: time,
-                    uid:   ent_header.uid().unwrap() as u32,
-                    gid:   ent_header.gid().unwrap() as u32,
+                    uid:   ent_header.uid().map_err(tio)? as u32,
+                    gid:   ent_header.gid().map_err(tio)? as u32,
                     nlink: 1,
-                    perm:  ent_header.mode().unwrap(),
+                    perm:  ent_header.mode().map_err(tio)?,
                     rdev:  0,
                 };
                 let ino = parcel.add_directory(attr, BTreeMap::new());
                 parcel
-                    .insert_dirent(
-                        parent_inode,
-                        entry_name.to_owned(),
-                        ino,
-                        InodeKind::Directory,
-                    )
-                    .unwrap();
+                    .insert_dirent(parent_inode, entry_name, ino, InodeKind::Directory)
+                    .map_err(pio)?;
                 dir_map.insert(entry_path, ino);
             }
-            _ => unimplemented!("TF:{:?}", header.typeflag),
+            tf => return Err(bad(&format!("unsupported entry type {:?}", tf))),
         }
     }
-    let parcelpath = get_parcel_path(provider, package);
-    std::fs::create_dir_all(parcelpath.parent().unwrap()).unwrap();
-    let file = File::create(parcelpath).unwrap();
-    parcel.set_file(Box::new(ReaderWriter::new(file)));
-    parcel.store().unwrap();
+    write_parcel(config, &PackageId::new(PREFIX, package), parcel)
 }
 
-pub fn parcel_build(package: &str) {
-    let package = &alpm_find_satisfier(package)[0];
+pub fn parcel_build(config: &Config, package: &str) -> Result<()> {
+    let package = &match config.overrides.contains_key(package) {
+        true => package.to_owned(),
+        false => alpm_find_satisfier(config, package)?,
+    };
 
-    if exists_parcel(ParcelProvider::Arch, package) {
-        return;
+    if parcel_current(config, &PackageId::new(PREFIX, package))? {
+        return Ok(());
     }
 
-    let (f, ext) = alpm_fetch(package);
-
-    match ext.as_str() {
-        "zst" => {
-            let dec = zstd::stream::read::Decoder::new(f).unwrap();
-            let archive = tar::Archive::new(dec);
-            parcel_from_pacman(ParcelProvider::Arch, package, archive);
-        }
-        "xz" => {
-            let dec = xz::read::XzDecoder::new(f);
-            let archive = tar::Archive::new(dec);
-            parcel_from_pacman(ParcelProvider::Arch, package, archive);
-        }
-        _ => unimplemented!("{}", ext),
-    };
+    let archive = tar::Archive::new(decompress(package, alpm_fetch(config, package)?)?);
+    parcel_from_pacman(config, package, archive)
 }
@@ -1,37 +1,149 @@
-use std::{collections::BTreeMap, ffi::OsString, fs::File, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::File,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
 
-use pyxis_parcel::{InodeAttr, InodeKind, ParcelHandle, ReaderWriter};
+use pyxis_parcel::{InodeAttr, InodeKind, ParcelHandle};
+use sha2::{Digest, Sha256};
 
-use super::recipe::Recipe;
-use crate::{get_home, get_parcel_path, ParcelProvider};
+use super::{recipe::Recipe, Provider};
+use crate::{
+    get_parcel_path, store::write_parcel, Config, PackageId, PackageSpec, PyxisError, Result,
+    VersionConstraint,
+};
 
-fn get_recipe_path(package: &str) -> PathBuf {
-    let mut buf = get_home();
-    buf.push(".pyxis/recipe/");
+pub const PREFIX: &str = "local";
+
+pub struct LocalProvider;
+
+impl Provider for LocalProvider {
+    fn prefix(&self) -> &str {
+        PREFIX
+    }
+
+    fn resolve(
+        &self,
+        config: &Config,
+        package: &str,
+        constraint: Option<&VersionConstraint>,
+    ) -> Result<String> {
+        let recipe = load_recipe(config, package)?;
+        match constraint {
+            Some(constraint) if !constraint.satisfied_by(&recipe.version) => {
+                Err(PyxisError::Resolution(format!(
+                    "recipe {} has version {}, which does not satisfy {}",
+                    package, recipe.version, constraint
+                )))
+            }
+            _ => Ok(package.to_owned()),
+        }
+    }
+
+    fn get_deps(&self, config: &Config, package: &str) -> Result<Vec<PackageSpec>> {
+        get_deps(config, package)?
+            .iter()
+            .map(|x| PackageSpec::parse(x))
+            .collect()
+    }
+
+    fn get_version(&self, config: &Config, package: &str) -> Result<String> {
+        get_version(config, package)
+    }
+
+    fn source(&self, config: &Config, package: &str) -> Result<Option<String>> {
+        recipe_source(config, package).map(Some)
+    }
+
+    fn parcel_build(&self, config: &Config, package: &str) -> Result<()> {
+        parcel_build(config, package)
+    }
+}
+
+fn get_recipe_path(config: &Config, package: &str) -> PathBuf {
+    let mut buf = config.recipe_root.clone();
     buf.push(package);
     buf
 }
 
-fn load_recipe(package: &str) -> Recipe {
-    let mut path = get_recipe_path(package);
+/// Load the recipe of a package. Only a missing recipe is a resolution
+/// failure, which lets resolution move on to the next provider; a recipe that
+/// exists but cannot be used is an error of its own.
+fn load_recipe(config: &Config, package: &str) -> Result<Recipe> {
+    let mut path = get_recipe_path(config, package);
     path.push("parcel.recipe");
-    let file =
-        File::open(path).unwrap_or_else(|_| panic!("Cannot find recipe file for {}", package));
-    serde_yaml::from_reader(file).unwrap()
+    let bad = |reason: String| PyxisError::Recipe {
+        path: path.clone(),
+        reason,
+    };
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(PyxisError::Resolution(format!(
+                "cannot find recipe file for {}",
+                package
+            )))
+        }
+        Err(e) => return Err(bad(e.to_string())),
+    };
+    serde_yaml::from_reader(file).map_err(|e| bad(e.to_string()))
 }
 
-pub fn get_deps(package: &str) -> Vec<String> {
-    let recipe = load_recipe(package);
-    recipe.depends
+/// Name a recipe by its directory and a digest of everything in it, so that
+/// editing its files or actions rebuilds the parcel even when the version
+/// stays the same
+fn recipe_source(config: &Config, package: &str) -> Result<String> {
+    let dir = get_recipe_path(config, package);
+    let bad = |e: std::io::Error| PyxisError::Recipe {
+        path:   dir.clone(),
+        reason: e.to_string(),
+    };
+    let dir = dir.canonicalize().map_err(bad)?;
+    let mut hasher = Sha256::new();
+    hash_dir(&dir, Path::new(""), &mut hasher).map_err(bad)?;
+    Ok(format!(
+        "{} (sha256 {:x})",
+        dir.display(),
+        hasher.finalize()
+    ))
 }
 
-pub fn _get_version(package: &str) -> String {
-    let recipe = load_recipe(package);
-    recipe.version
+/// Feed the names and contents of everything below `root.join(rel)` to the
+/// hasher, in a fixed order
+fn hash_dir(root: &Path, rel: &Path, hasher: &mut Sha256) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(root.join(rel))?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let rel = rel.join(entry.file_name());
+        hasher.update(rel.as_os_str().as_bytes());
+        hasher.update([0]);
+        if entry.path().is_dir() {
+            hash_dir(root, &rel, hasher)?;
+        } else {
+            let mut file = File::open(entry.path())?;
+            hasher.update(file.metadata()?.len().to_le_bytes());
+            std::io::copy(&mut file, hasher)?;
+        }
+    }
+    Ok(())
 }
 
-pub fn parcel_build(package: &str) {
-    let recipe = load_recipe(package);
+pub fn get_deps(config: &Config, package: &str) -> Result<Vec<String>> {
+    let recipe = load_recipe(config, package)?;
+    Ok(recipe.depends)
+}
+
+pub fn get_version(config: &Config, package: &str) -> Result<String> {
+    let recipe = load_recipe(config, package)?;
+    Ok(recipe.version)
+}
+
+pub fn parcel_build(config: &Config, package: &str) -> Result<()> {
+    let recipe = load_recipe(config, package)?;
+    let parcelpath = get_parcel_path(config, PREFIX, package);
+    let pio = |e| PyxisError::parcel_io(&parcelpath, e);
 
     let mut parcel = ParcelHandle::new();
 
@@ -59,15 +171,15 @@ pub fn parcel_build(package: &str) {
             pyxis_dir,
             InodeKind::Directory,
         )
-        .unwrap();
+        .map_err(pio)?;
     parcel
         .insert_dirent(
             pyxis_dir,
-            std::ffi::OsString::from("local"),
+            std::ffi::OsString::from(PREFIX),
             provider_dir,
             InodeKind::Directory,
         )
-        .unwrap();
+        .map_err(pio)?;
     parcel
         .insert_dirent(
             provider_dir,
@@ -75,23 +187,37 @@ pub fn parcel_build(package: &str) {
             parcel_dir,
             InodeKind::Directory,
         )
-        .unwrap();
+        .map_err(pio)?;
 
     for (source, dest) in recipe.files {
-        let mut path = get_recipe_path(package);
+        let mut path = get_recipe_path(config, package);
         path.push(source);
 
+        let dest = PathBuf::from(dest);
+        let (parent_path, file_name) = match (dest.parent(), dest.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => {
+                return Err(PyxisError::Resolution(format!(
+                    "recipe {} installs to invalid path {}",
+                    package,
+                    dest.display()
+                )))
+            }
+        };
+
         let mut pathsofar = PathBuf::new();
-        let mut parent = 0;
-        for comp in PathBuf::from(dest.clone()).parent().unwrap().iter() {
+        let mut parent = 1;
+        for comp in parent_path.iter() {
             pathsofar.push(comp);
-            if parcel.select(pathsofar.clone()) == None {
+            if parcel.select(pathsofar.clone()).is_none() {
                 let dir = parcel.add_directory(attr, BTreeMap::new());
                 parcel
                     .insert_dirent(parent, comp.to_owned(), dir, InodeKind::Directory)
-                    .unwrap();
+                    .map_err(pio)?;
             }
-            parent = parcel.select(pathsofar.clone()).unwrap();
+            parent = parcel.select(pathsofar.clone()).ok_or_else(|| {
+                PyxisError::parcel_io(&parcelpath, format!("lost {}", pathsofar.display()))
+            })?;
         }
         let ino = parcel
             .add_file(
@@ -99,19 +225,19 @@ pub fn parcel_build(package: &str) {
                 attr,
                 BTreeMap::new(),
             )
-            .unwrap();
+            .map_err(|e| PyxisError::parcel_io(&path, e))?;
         parcel
             .insert_dirent(
                 parent,
-                OsString::from(PathBuf::from(dest).file_name().unwrap()),
+                OsString::from(file_name),
                 ino,
                 InodeKind::RegularFile,
             )
-            .unwrap();
+            .map_err(pio)?;
     }
 
     if let Some(actions) = recipe.actions {
-        let mut path = get_recipe_path(package);
+        let mut path = get_recipe_path(config, package);
         path.push(actions);
         let ino = parcel
             .add_file(
@@ -119,7 +245,7 @@ pub fn parcel_build(package: &str) {
                 attr,
                 BTreeMap::new(),
             )
-            .unwrap();
+            .map_err(|e| PyxisError::parcel_io(&path, e))?;
         parcel
             .insert_dirent(
                 parcel_dir,
@@ -127,12 +253,38 @@ pub fn parcel_build(package: &str) {
                 ino,
                 InodeKind::RegularFile,
             )
-            .unwrap();
+            .map_err(pio)?;
     }
 
-    let parcelpath = get_parcel_path(ParcelProvider::Local, package);
-    std::fs::create_dir_all(parcelpath.parent().unwrap()).unwrap();
-    let file = File::create(parcelpath).unwrap();
-    parcel.set_file(Box::new(ReaderWriter::new(file)));
-    parcel.store().unwrap();
+    write_parcel(config, &PackageId::new(PREFIX, package), parcel)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn source_follows_recipe_contents() {
+        let recipes = tempfile::tempdir().unwrap();
+        let config = Config {
+            recipe_root: recipes.path().to_owned(),
+            ..Config::default()
+        };
+        let dir = recipes.path().join("hello");
+        fs::create_dir_all(dir.join("files")).unwrap();
+        fs::write(dir.join("parcel.recipe"), "version: 1.0-1\n").unwrap();
+        fs::write(dir.join("files/hello.txt"), "hello").unwrap();
+
+        let source = recipe_source(&config, "hello").unwrap();
+        assert_eq!(recipe_source(&config, "hello").unwrap(), source);
+
+        fs::write(dir.join("files/hello.txt"), "hello again").unwrap();
+        let edited = recipe_source(&config, "hello").unwrap();
+        assert_ne!(edited, source);
+
+        fs::rename(dir.join("files/hello.txt"), dir.join("files/renamed.txt")).unwrap();
+        assert_ne!(recipe_source(&config, "hello").unwrap(), edited);
+    }
 }
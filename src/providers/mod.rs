@@ -1,3 +1,159 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::{Config, PackageSpec, PyxisError, Result, VersionConstraint};
+
 pub mod alpm;
 pub mod local;
 pub mod recipe;
+pub mod upper;
+
+/// How a package relates to packages other than its dependencies, as
+/// dependency strings such as `sh` or `iptables=1:1.8.8-1`
+#[derive(Debug, Clone, Default)]
+pub struct Relations {
+    /// Names the package can stand in for
+    pub provides:  Vec<String>,
+    /// Packages that cannot be in the same image
+    pub conflicts: Vec<String>,
+    /// Packages it supersedes, which cannot be in the same image either
+    pub replaces:  Vec<String>,
+}
+
+/// An optional dependency of a package
+#[derive(Debug, Clone, Serialize)]
+pub struct OptDep {
+    /// The dependency string, e.g. `python-pyqt5`
+    pub dep:         String,
+    /// What the dependency adds, if the package says
+    pub description: Option<String>,
+}
+
+impl OptDep {
+    /// Split a pacman optdepend such as `python-pyqt5: Qt5 backend`. Like
+    /// libalpm, only `": "` separates the description, so that an epoch in a
+    /// versioned optdepend such as `foo>=1:2.0-1: desc` stays in the dep.
+    pub fn parse(optdep: &str) -> OptDep {
+        match optdep.split_once(": ") {
+            Some((dep, description)) => OptDep {
+                dep:         dep.trim().to_owned(),
+                description: Some(description.trim().to_owned()),
+            },
+            None => OptDep {
+                dep:         optdep.trim().to_owned(),
+                description: None,
+            },
+        }
+    }
+}
+
+/// A source of packages. Each provider owns one prefix, which names it in
+/// `provider|package` specs and is its directory in the parcel store.
+pub trait Provider: Send + Sync {
+    /// The prefix this provider is registered under
+    fn prefix(&self) -> &str;
+    /// Map a requested name to the name of the package that satisfies it,
+    /// failing if no package meets the version constraint
+    fn resolve(
+        &self,
+        config: &Config,
+        package: &str,
+        constraint: Option<&VersionConstraint>,
+    ) -> Result<String>;
+    /// List the direct dependencies of a package, as unresolved specs
+    fn get_deps(&self, config: &Config, package: &str) -> Result<Vec<PackageSpec>>;
+    /// Get the version of a package
+    fn get_version(&self, config: &Config, package: &str) -> Result<String>;
+    /// What a package provides, conflicts with and replaces. Providers whose
+    /// packages carry no such metadata keep the default of none.
+    fn relations(&self, _config: &Config, _package: &str) -> Result<Relations> {
+        Ok(Relations::default())
+    }
+    /// The member packages of a group, which manifests name as `@group`.
+    /// Providers without groups keep the default, which has none.
+    fn group_members(&self, _config: &Config, group: &str) -> Result<Vec<String>> {
+        Err(PyxisError::Resolution(format!(
+            "no group '{}'; {} has no package groups",
+            group,
+            self.prefix()
+        )))
+    }
+    /// The optional dependencies of a package, which are only installed
+    /// when the manifest asks for them
+    fn optdeps(&self, _config: &Config, _package: &str) -> Result<Vec<OptDep>> {
+        Ok(Vec::new())
+    }
+    /// What a package's parcel is built from, when that can change without
+    /// its version changing, such as a local file standing in for a
+    /// repository package or a recipe edited in place. A stored parcel from
+    /// another source is rebuilt.
+    /// Providers with a single source keep the default of none.
+    fn source(&self, _config: &Config, _package: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+    /// Build the parcel for a package into the store, if it is not there yet
+    fn parcel_build(&self, config: &Config, package: &str) -> Result<()>;
+}
+
+lazy_static! {
+    static ref PROVIDERS: RwLock<BTreeMap<String, Arc<dyn Provider>>> = {
+        let mut providers: BTreeMap<String, Arc<dyn Provider>> = BTreeMap::new();
+        for provider in [
+            Arc::new(alpm::AlpmProvider) as Arc<dyn Provider>,
+            Arc::new(local::LocalProvider),
+            Arc::new(upper::UpperProvider),
+        ] {
+            providers.insert(provider.prefix().to_owned(), provider);
+        }
+        RwLock::new(providers)
+    };
+}
+
+/// Register a provider, replacing any provider with the same prefix
+pub fn register_provider(provider: Arc<dyn Provider>) {
+    PROVIDERS
+        .write()
+        .unwrap()
+        .insert(provider.prefix().to_owned(), provider);
+}
+
+/// Look up the provider registered under `prefix`
+pub fn get_provider(prefix: &str) -> Result<Arc<dyn Provider>> {
+    PROVIDERS
+        .read()
+        .unwrap()
+        .get(prefix)
+        .cloned()
+        .ok_or_else(|| PyxisError::Resolution(format!("unknown provider '{}'", prefix)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OptDep;
+
+    #[test]
+    fn optdep_with_description() {
+        let optdep = OptDep::parse("python-pyqt5: Qt5 backend");
+        assert_eq!(optdep.dep, "python-pyqt5");
+        assert_eq!(optdep.description.as_deref(), Some("Qt5 backend"));
+    }
+
+    #[test]
+    fn optdep_without_description() {
+        let optdep = OptDep::parse("python-pyqt5");
+        assert_eq!(optdep.dep, "python-pyqt5");
+        assert_eq!(optdep.description, None);
+    }
+
+    #[test]
+    fn optdep_keeps_epoch_in_version() {
+        let optdep = OptDep::parse("foo>=1:2.0-1: desc: with colons");
+        assert_eq!(optdep.dep, "foo>=1:2.0-1");
+        assert_eq!(optdep.description.as_deref(), Some("desc: with colons"));
+    }
+}
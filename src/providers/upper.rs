@@ -0,0 +1,347 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::File,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use pyxis_parcel::{InodeAttr, InodeKind, ParcelHandle, ReaderWriter};
+use sha2::{Digest, Sha256};
+
+use super::Provider;
+use crate::{
+    exists_parcel, get_parcel_path, get_provider, store::write_parcel, Config, PackageId,
+    PackageSpec, PyxisError, Result, VersionConstraint,
+};
+
+pub const PREFIX: &str = "Upper";
+
+/// Top-level directories that hold API filesystems while scriptlets and hooks
+/// run. Their contents are never captured.
+const SKIP_DIRS: [&str; 4] = ["proc", "sys", "dev", "tmp"];
+
+/// Upper parcels hold the changes scriptlets and hooks made on top of an image's
+/// package parcels. They are named by a digest of the image's install set and
+/// can only be produced by an image build.
+pub struct UpperProvider;
+
+impl Provider for UpperProvider {
+    fn prefix(&self) -> &str {
+        PREFIX
+    }
+
+    fn resolve(
+        &self,
+        config: &Config,
+        package: &str,
+        constraint: Option<&VersionConstraint>,
+    ) -> Result<String> {
+        if !exists_parcel(config, PREFIX, package) {
+            return Err(PyxisError::Resolution(format!(
+                "no upper parcel named {}",
+                package
+            )));
+        }
+        if let Some(constraint) = constraint {
+            let version = self.get_version(config, package)?;
+            if !constraint.satisfied_by(&version) {
+                return Err(PyxisError::Resolution(format!(
+                    "upper parcel {} has version {}, which does not satisfy {}",
+                    package, version, constraint
+                )));
+            }
+        }
+        Ok(package.to_owned())
+    }
+
+    fn get_deps(&self, _config: &Config, _package: &str) -> Result<Vec<PackageSpec>> {
+        Ok(Vec::new())
+    }
+
+    fn get_version(&self, config: &Config, package: &str) -> Result<String> {
+        let path = get_parcel_path(config, PREFIX, package);
+        let f = File::open(&path).map_err(|e| PyxisError::parcel_io(&path, e))?;
+        let mut parcel = ParcelHandle::load(Box::new(ReaderWriter::new(f)))
+            .map_err(|e| PyxisError::parcel_io(&path, e))?;
+        Ok(parcel.metadata().version.clone())
+    }
+
+    fn parcel_build(&self, config: &Config, package: &str) -> Result<()> {
+        self.resolve(config, package, None)
+            .map(|_| ())
+            .map_err(|_| {
+                PyxisError::Resolution(format!(
+                    "upper parcel {} can only be captured by an image build",
+                    package
+                ))
+            })
+    }
+}
+
+/// Name of the upper parcel for an install set. Any change to the packages,
+/// their order, their versions or their sources gives a different name.
+pub fn upper_name(config: &Config, to_install: &[PackageId]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for package in to_install {
+        let provider = get_provider(&package.provider)?;
+        let version = provider.get_version(config, &package.name)?;
+        match provider.source(config, &package.name)? {
+            Some(source) => hasher.update(format!("{}={} {}\n", package, version, source)),
+            None => hasher.update(format!("{}={}\n", package, version)),
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[derive(PartialEq, Eq)]
+enum EntryKind {
+    Directory,
+    File,
+    Symlink(PathBuf),
+    Other,
+}
+
+#[derive(PartialEq, Eq)]
+struct EntryState {
+    kind:  EntryKind,
+    mode:  u32,
+    uid:   u32,
+    gid:   u32,
+    size:  u64,
+    mtime: (i64, i64),
+    ino:   u64,
+}
+
+/// The state of every entry in an image tree, keyed by path relative to its root
+pub struct Snapshot(BTreeMap<PathBuf, EntryState>);
+
+pub fn snapshot(root: &Path) -> Result<Snapshot> {
+    let mut entries = BTreeMap::new();
+    walk(root, Path::new(""), &mut entries)?;
+    Ok(Snapshot(entries))
+}
+
+fn walk(root: &Path, rel: &Path, entries: &mut BTreeMap<PathBuf, EntryState>) -> Result<()> {
+    for ent in std::fs::read_dir(root.join(rel))? {
+        let ent = ent?;
+        let rel = rel.join(ent.file_name());
+        if rel.parent() == Some(Path::new("")) && SKIP_DIRS.iter().any(|d| rel == Path::new(d)) {
+            continue;
+        }
+        let meta = ent.metadata()?;
+        let file_type = meta.file_type();
+        let kind = if file_type.is_dir() {
+            EntryKind::Directory
+        } else if file_type.is_file() {
+            EntryKind::File
+        } else if file_type.is_symlink() {
+            EntryKind::Symlink(std::fs::read_link(ent.path())?)
+        } else {
+            EntryKind::Other
+        };
+        // A directory's size and times change whenever its children do, which
+        // the children already account for.
+        let (size, mtime, ino) = match kind {
+            EntryKind::Directory => (0, (0, 0), 0),
+            _ => (meta.size(), (meta.mtime(), meta.mtime_nsec()), meta.ino()),
+        };
+        let recurse = kind == EntryKind::Directory;
+        entries.insert(
+            rel.clone(),
+            EntryState {
+                kind,
+                mode: meta.mode(),
+                uid: meta.uid(),
+                gid: meta.gid(),
+                size,
+                mtime,
+                ino,
+            },
+        );
+        if recurse {
+            walk(root, &rel, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Capture every difference between `before` and the current state of `root`
+/// into the upper parcel `name`. Deleted entries become whiteouts.
+pub fn capture(
+    config: &Config,
+    root: &Path,
+    before: &Snapshot,
+    name: &str,
+    to_install: &[PackageId],
+) -> Result<()> {
+    let after = snapshot(root)?;
+    let parcelpath = get_parcel_path(config, PREFIX, name);
+    let pio = |e| PyxisError::parcel_io(&parcelpath, e);
+
+    let mut parcel = ParcelHandle::new();
+    parcel.metadata().version = name.to_owned();
+    parcel.metadata().depends = to_install.iter().map(|p| p.to_string()).collect();
+
+    let mut dirs: BTreeMap<PathBuf, u64> = BTreeMap::new();
+    dirs.insert(PathBuf::new(), 1);
+
+    for (rel, state) in &after.0 {
+        if before.0.get(rel) == Some(state) {
+            continue;
+        }
+        let (parent, file_name) = split(rel)?;
+        let parent = ensure_dir(&mut parcel, &mut dirs, root, parent, &parcelpath)?;
+        let path = root.join(rel);
+        let attr = attr_of(&path)?;
+        match &state.kind {
+            EntryKind::Directory => {
+                if !dirs.contains_key(rel) {
+                    let ino = parcel.add_directory(attr, BTreeMap::new());
+                    parcel
+                        .insert_dirent(parent, file_name, ino, InodeKind::Directory)
+                        .map_err(pio)?;
+                    dirs.insert(rel.clone(), ino);
+                }
+            }
+            EntryKind::File => {
+                let ino = parcel
+                    .add_file(
+                        pyxis_parcel::FileAdd::Name(path.into_os_string()),
+                        attr,
+                        BTreeMap::new(),
+                    )
+                    .map_err(pio)?;
+                parcel
+                    .insert_dirent(parent, file_name, ino, InodeKind::RegularFile)
+                    .map_err(pio)?;
+            }
+            EntryKind::Symlink(target) => {
+                let ino = parcel
+                    .add_symlink(target.clone().into_os_string(), attr, BTreeMap::new())
+                    .map_err(pio)?;
+                parcel
+                    .insert_dirent(parent, file_name, ino, InodeKind::Symlink)
+                    .map_err(pio)?;
+            }
+            EntryKind::Other => {}
+        }
+    }
+
+    for rel in before.0.keys() {
+        if after.0.contains_key(rel) {
+            continue;
+        }
+        let (parent_path, file_name) = split(rel)?;
+        // Removing a directory already removes everything below it
+        if !parent_path.as_os_str().is_empty() && !after.0.contains_key(parent_path) {
+            continue;
+        }
+        let parent = ensure_dir(&mut parcel, &mut dirs, root, parent_path, &parcelpath)?;
+        parcel.insert_whiteout(parent, file_name).map_err(pio)?;
+    }
+
+    write_parcel(config, &PackageId::new(PREFIX, name), parcel)
+}
+
+fn split(rel: &Path) -> Result<(&Path, OsString)> {
+    match (rel.parent(), rel.file_name()) {
+        (Some(parent), Some(name)) => Ok((parent, name.to_owned())),
+        _ => Err(PyxisError::parcel_io(rel, "cannot capture path")),
+    }
+}
+
+fn attr_of(path: &Path) -> Result<InodeAttr> {
+    let meta = std::fs::symlink_metadata(path)?;
+    let mut attr = InodeAttr::from_meta(&meta);
+    attr.perm &= 0o7777;
+    Ok(attr)
+}
+
+/// Make sure `rel` and all its ancestors exist as directories in the parcel,
+/// copying their attributes from the image tree, and return its inode.
+fn ensure_dir(
+    parcel: &mut ParcelHandle,
+    dirs: &mut BTreeMap<PathBuf, u64>,
+    root: &Path,
+    rel: &Path,
+    parcelpath: &Path,
+) -> Result<u64> {
+    if let Some(ino) = dirs.get(rel) {
+        return Ok(*ino);
+    }
+    let (parent, file_name) = split(rel)?;
+    let parent = ensure_dir(parcel, dirs, root, parent, parcelpath)?;
+    let ino = parcel.add_directory(attr_of(&root.join(rel))?, BTreeMap::new());
+    parcel
+        .insert_dirent(parent, file_name, ino, InodeKind::Directory)
+        .map_err(|e| PyxisError::parcel_io(parcelpath, e))?;
+    dirs.insert(rel.to_owned(), ino);
+    Ok(ino)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// The entries of a directory in a parcel, by name
+    fn entries(parcel: &ParcelHandle, dir: u64) -> BTreeMap<String, InodeKind> {
+        parcel
+            .readdir(dir)
+            .unwrap()
+            .into_iter()
+            .map(|(_, kind, name)| (name, kind))
+            .filter(|(name, _)| name != "." && name != "..")
+            .collect()
+    }
+
+    #[test]
+    fn capture_changes_and_whiteouts() {
+        let store = tempfile::tempdir().unwrap();
+        let config = Config {
+            parcel_root: store.path().to_owned(),
+            ..Config::default()
+        };
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        for dir in ["etc", "usr/share/doc", "tmp"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in ["etc/kept", "etc/removed", "usr/share/doc/README"] {
+            fs::write(root.join(file), "before").unwrap();
+        }
+
+        let before = snapshot(root).unwrap();
+        fs::write(root.join("etc/added"), "after").unwrap();
+        fs::remove_file(root.join("etc/removed")).unwrap();
+        fs::remove_dir_all(root.join("usr/share")).unwrap();
+        fs::write(root.join("tmp/scratch"), "ignored").unwrap();
+        capture(&config, root, &before, "test", &[]).unwrap();
+
+        let path = get_parcel_path(&config, PREFIX, "test");
+        let parcel =
+            ParcelHandle::load(Box::new(ReaderWriter::new(File::open(path).unwrap()))).unwrap();
+        let top = entries(&parcel, 1);
+        assert_eq!(
+            top.keys().collect::<Vec<_>>(),
+            vec!["etc", "usr"],
+            "unchanged and skipped directories are left out"
+        );
+        let etc = entries(&parcel, parcel.lookup(1, "etc").unwrap());
+        assert_eq!(
+            etc,
+            BTreeMap::from([
+                ("added".to_owned(), InodeKind::RegularFile),
+                ("removed".to_owned(), InodeKind::Whiteout),
+            ])
+        );
+        // Only the removed directory itself is whited out, not its contents
+        let usr = entries(&parcel, parcel.lookup(1, "usr").unwrap());
+        assert_eq!(
+            usr,
+            BTreeMap::from([("share".to_owned(), InodeKind::Whiteout)])
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use indexmap::IndexMap;
+
+use crate::{
+    get_provider, resolve_spec, spec::parse_dep, Config, ConstraintOp, PackageId, PackageSpec,
+    PyxisError, Relations, Result, VersionConstraint,
+};
+
+/// The packages chosen for an image so far, with their versions and
+/// relations, so that later dependencies can be satisfied by them and
+/// conflicts between them found
+#[derive(Default)]
+pub(crate) struct Chosen {
+    packages: IndexMap<PackageId, (String, Relations)>,
+}
+
+impl Chosen {
+    /// Resolve a spec to a chosen package that satisfies it by name or
+    /// provides, falling back to resolving it afresh and choosing the result
+    pub fn resolve(&mut self, config: &Config, spec: &PackageSpec) -> Result<PackageId> {
+        self.resolve_from(config, spec, false)
+    }
+
+    /// Resolve a dependency like `resolve`, except that a package chosen from
+    /// a provider earlier in `config.provider_order` also satisfies a
+    /// dependency on a later one. This way a `local` package named in the
+    /// manifest stands in for the `arch` package of the same name everywhere.
+    pub fn resolve_dep(&mut self, config: &Config, spec: &PackageSpec) -> Result<PackageId> {
+        self.resolve_from(config, spec, true)
+    }
+
+    fn resolve_from(
+        &mut self,
+        config: &Config,
+        spec: &PackageSpec,
+        shadow: bool,
+    ) -> Result<PackageId> {
+        if let Some(package) = self.satisfier(config, spec, shadow) {
+            return Ok(package.clone());
+        }
+        let package = resolve_spec(config, spec)?;
+        self.add(config, &package)?;
+        Ok(package)
+    }
+
+    fn add(&mut self, config: &Config, package: &PackageId) -> Result<()> {
+        if self.packages.contains_key(package) {
+            return Ok(());
+        }
+        let provider = get_provider(&package.provider)?;
+        let version = provider.get_version(config, &package.name)?;
+        let relations = provider.relations(config, &package.name)?;
+        self.packages.insert(package.clone(), (version, relations));
+        Ok(())
+    }
+
+    fn satisfier(&self, config: &Config, spec: &PackageSpec, shadow: bool) -> Option<&PackageId> {
+        self.packages
+            .iter()
+            .filter(|(id, _)| {
+                spec.provider.as_ref().is_none_or(|p| {
+                    *p == id.provider || (shadow && precedes(config, &id.provider, p))
+                })
+            })
+            .find(|(id, (version, relations))| {
+                satisfies(id, version, relations, &spec.name, spec.constraint.as_ref())
+            })
+            .map(|(id, _)| id)
+    }
+
+    /// Fail if a chosen package conflicts with or replaces another one, or if
+    /// the same package was chosen from two providers
+    pub fn check_conflicts(&self) -> Result<()> {
+        for (package, (_, relations)) in &self.packages {
+            if let Some(other) = self
+                .packages
+                .keys()
+                .find(|other| other.name == package.name && other.provider != package.provider)
+            {
+                return Err(PyxisError::Resolution(format!(
+                    "both {} and {} were chosen; list the one to use in the manifest so that it \
+                     satisfies every dependency on {}",
+                    package, other, package.name
+                )));
+            }
+            let against = relations
+                .conflicts
+                .iter()
+                .map(|dep| ("conflicts with", dep))
+                .chain(relations.replaces.iter().map(|dep| ("replaces", dep)));
+            for (verb, dep) in against {
+                let (name, constraint) = parse_dep(dep)?;
+                let other = self.packages.iter().find(|(other, (version, other_rel))| {
+                    *other != package
+                        && other.provider == package.provider
+                        && satisfies(other, version, other_rel, &name, constraint.as_ref())
+                });
+                if let Some((other, _)) = other {
+                    return Err(PyxisError::Resolution(format!(
+                        "{} {} '{}', which {} satisfies; list the one to use in the \
+                         manifest and drop the other",
+                        package, verb, dep, other
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `provider` comes before `other` in `config.provider_order`
+fn precedes(config: &Config, provider: &str, other: &str) -> bool {
+    let rank = |p: &str| config.provider_order.iter().position(|o| o == p);
+    match (rank(provider), rank(other)) {
+        (Some(provider), Some(other)) => provider < other,
+        _ => false,
+    }
+}
+
+/// Whether a package satisfies `name` with an optional version constraint,
+/// either itself or through one of its provides. As in pacman, a versioned
+/// requirement is only met by a provide that carries an exact version.
+fn satisfies(
+    package: &PackageId,
+    version: &str,
+    relations: &Relations,
+    name: &str,
+    constraint: Option<&VersionConstraint>,
+) -> bool {
+    if package.name == name && constraint.is_none_or(|c| c.satisfied_by(version)) {
+        return true;
+    }
+    relations
+        .provides
+        .iter()
+        .any(|provide| match parse_dep(provide) {
+            Ok((provided, provided_version)) if provided == name => match constraint {
+                None => true,
+                Some(c) => provided_version
+                    .filter(|v| v.op == ConstraintOp::Eq)
+                    .is_some_and(|v| c.satisfied_by(&v.version)),
+            },
+            _ => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provides(provides: &[&str]) -> Relations {
+        Relations {
+            provides: provides.iter().map(|p| p.to_string()).collect(),
+            ..Relations::default()
+        }
+    }
+
+    fn constraint(dep: &str) -> Option<VersionConstraint> {
+        parse_dep(dep).unwrap().1
+    }
+
+    #[test]
+    fn satisfied_by_name_and_version() {
+        let package = PackageId::new("arch", "glibc");
+        let relations = Relations::default();
+        assert!(satisfies(&package, "2.36-1", &relations, "glibc", None));
+        assert!(satisfies(
+            &package,
+            "2.36-1",
+            &relations,
+            "glibc",
+            constraint("glibc>=2.35").as_ref()
+        ));
+        assert!(!satisfies(
+            &package,
+            "2.34-1",
+            &relations,
+            "glibc",
+            constraint("glibc>=2.35").as_ref()
+        ));
+        assert!(!satisfies(&package, "2.36-1", &relations, "musl", None));
+    }
+
+    #[test]
+    fn satisfied_by_versioned_provide() {
+        let package = PackageId::new("arch", "iptables-nft");
+        let relations = provides(&["iptables=1.8.8-1"]);
+        assert!(satisfies(&package, "1.8.8-1", &relations, "iptables", None));
+        assert!(satisfies(
+            &package,
+            "1.8.8-1",
+            &relations,
+            "iptables",
+            constraint("iptables>=1.8").as_ref()
+        ));
+        assert!(!satisfies(
+            &package,
+            "1.8.8-1",
+            &relations,
+            "iptables",
+            constraint("iptables>=1.9").as_ref()
+        ));
+    }
+
+    #[test]
+    fn unversioned_provide_does_not_meet_versioned_requirement() {
+        let package = PackageId::new("arch", "bash");
+        let relations = provides(&["sh"]);
+        assert!(satisfies(&package, "5.1-1", &relations, "sh", None));
+        assert!(!satisfies(
+            &package,
+            "5.1-1",
+            &relations,
+            "sh",
+            constraint("sh>=1").as_ref()
+        ));
+    }
+
+    #[test]
+    fn non_exact_provide_does_not_meet_versioned_requirement() {
+        let package = PackageId::new("arch", "foo");
+        let relations = provides(&["bar>=2"]);
+        assert!(!satisfies(
+            &package,
+            "1-1",
+            &relations,
+            "bar",
+            constraint("bar>=1").as_ref()
+        ));
+    }
+
+    fn chosen(packages: &[PackageId]) -> Chosen {
+        Chosen {
+            packages: packages
+                .iter()
+                .map(|p| (p.clone(), ("1-1".to_owned(), Relations::default())))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn earlier_provider_satisfies_dependency() {
+        let config = Config {
+            provider_order: vec!["local".to_owned(), "arch".to_owned()],
+            ..Config::default()
+        };
+        let chosen = chosen(&[PackageId::new("local", "bash")]);
+        let spec = PackageSpec::parse("arch|bash").unwrap();
+        assert_eq!(
+            chosen.satisfier(&config, &spec, true),
+            Some(&PackageId::new("local", "bash"))
+        );
+        assert_eq!(chosen.satisfier(&config, &spec, false), None);
+
+        let config = Config {
+            provider_order: vec!["arch".to_owned(), "local".to_owned()],
+            ..config
+        };
+        assert_eq!(chosen.satisfier(&config, &spec, true), None);
+    }
+
+    #[test]
+    fn same_name_from_two_providers_fails() {
+        let chosen = chosen(&[
+            PackageId::new("local", "bash"),
+            PackageId::new("arch", "bash"),
+        ]);
+        assert!(matches!(
+            chosen.check_conflicts(),
+            Err(PyxisError::Resolution(_))
+        ));
+    }
+}
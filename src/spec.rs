@@ -0,0 +1,207 @@
+use std::{cmp::Ordering, fmt};
+
+use crate::{PackageId, PyxisError, Result};
+
+#[derive(Debug, Hash, Eq, PartialEq, Copy, Clone)]
+pub enum ConstraintOp {
+    Eq,
+    Ge,
+    Le,
+    Gt,
+    Lt,
+}
+
+impl ConstraintOp {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConstraintOp::Eq => "=",
+            ConstraintOp::Ge => ">=",
+            ConstraintOp::Le => "<=",
+            ConstraintOp::Gt => ">",
+            ConstraintOp::Lt => "<",
+        }
+    }
+}
+
+/// A version requirement such as `>=2.35` or `=1.2-3`
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct VersionConstraint {
+    pub op:      ConstraintOp,
+    pub version: String,
+}
+
+impl VersionConstraint {
+    /// Check a version against the constraint with pacman's vercmp rules. A
+    /// constraint without a pkgrel matches every pkgrel of its version.
+    pub fn satisfied_by(&self, version: &str) -> bool {
+        let ord = alpm::vercmp(version, self.version.as_str());
+        match self.op {
+            ConstraintOp::Eq => ord == Ordering::Equal,
+            ConstraintOp::Ge => ord != Ordering::Less,
+            ConstraintOp::Le => ord != Ordering::Greater,
+            ConstraintOp::Gt => ord == Ordering::Greater,
+            ConstraintOp::Lt => ord == Ordering::Less,
+        }
+    }
+}
+
+impl fmt::Display for VersionConstraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.op.as_str(), self.version)
+    }
+}
+
+/// Split a pacman-style dependency string such as `glibc>=2.35` into the
+/// package name and its optional version constraint.
+pub fn parse_dep(dep: &str) -> Result<(String, Option<VersionConstraint>)> {
+    let bad = || PyxisError::Resolution(format!("invalid dependency '{}'", dep));
+    let (name, constraint) = match dep.find(['<', '>', '=']) {
+        None => (dep, None),
+        Some(pos) => {
+            let (name, rest) = dep.split_at(pos);
+            let (op, version) = [
+                ConstraintOp::Ge,
+                ConstraintOp::Le,
+                ConstraintOp::Eq,
+                ConstraintOp::Gt,
+                ConstraintOp::Lt,
+            ]
+            .into_iter()
+            .find_map(|op| rest.strip_prefix(op.as_str()).map(|v| (op, v)))
+            .ok_or_else(bad)?;
+            if version.is_empty() || version.contains(['<', '>', '=']) {
+                return Err(bad());
+            }
+            (
+                name,
+                Some(VersionConstraint {
+                    op,
+                    version: version.to_owned(),
+                }),
+            )
+        }
+    };
+    if name.is_empty() {
+        return Err(bad());
+    }
+    Ok((name.to_owned(), constraint))
+}
+
+/// A request for a package, written `provider|name` with an optional version
+/// constraint, e.g. `arch|glibc>=2.35`. The provider may be left out, in which
+/// case the providers in `Config::provider_order` are tried in turn.
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct PackageSpec {
+    pub provider:   Option<String>,
+    pub name:       String,
+    pub constraint: Option<VersionConstraint>,
+}
+
+impl PackageSpec {
+    pub fn new(provider: &str, name: &str, constraint: Option<VersionConstraint>) -> PackageSpec {
+        PackageSpec {
+            provider: Some(provider.to_owned()),
+            name: name.to_owned(),
+            constraint,
+        }
+    }
+
+    pub fn parse(spec: &str) -> Result<PackageSpec> {
+        let (provider, name) = if spec.contains('|') {
+            let id = PackageId::parse(spec)?;
+            (Some(id.provider), id.name)
+        } else {
+            (None, spec.to_owned())
+        };
+        let (name, constraint) = parse_dep(&name)?;
+        Ok(PackageSpec {
+            provider,
+            name,
+            constraint,
+        })
+    }
+}
+
+impl fmt::Display for PackageSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(provider) = &self.provider {
+            write!(f, "{}|", provider)?;
+        }
+        write!(f, "{}", self.name)?;
+        if let Some(constraint) = &self.constraint {
+            write!(f, "{}", constraint)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constraint(op: ConstraintOp, version: &str) -> VersionConstraint {
+        VersionConstraint {
+            op,
+            version: version.to_owned(),
+        }
+    }
+
+    #[test]
+    fn dep_without_constraint() {
+        assert_eq!(parse_dep("glibc").unwrap(), ("glibc".to_owned(), None));
+    }
+
+    #[test]
+    fn dep_with_constraint() {
+        for (dep, op, version) in [
+            ("glibc>=2.35", ConstraintOp::Ge, "2.35"),
+            ("glibc<=2.35", ConstraintOp::Le, "2.35"),
+            ("glibc=2.35-1", ConstraintOp::Eq, "2.35-1"),
+            ("glibc>2.35", ConstraintOp::Gt, "2.35"),
+            ("glibc<2.35", ConstraintOp::Lt, "2.35"),
+        ] {
+            assert_eq!(
+                parse_dep(dep).unwrap(),
+                ("glibc".to_owned(), Some(constraint(op, version))),
+                "{}",
+                dep
+            );
+        }
+    }
+
+    #[test]
+    fn dep_malformed() {
+        for dep in ["", ">=2.35", "glibc>=", "glibc=>2.35", "glibc>=2<3"] {
+            assert!(parse_dep(dep).is_err(), "{}", dep);
+        }
+    }
+
+    #[test]
+    fn constraint_without_pkgrel_matches_every_pkgrel() {
+        let eq = constraint(ConstraintOp::Eq, "2.35");
+        assert!(eq.satisfied_by("2.35-1"));
+        assert!(eq.satisfied_by("2.35-7"));
+        assert!(!eq.satisfied_by("2.36-1"));
+    }
+
+    #[test]
+    fn constraint_with_pkgrel() {
+        let eq = constraint(ConstraintOp::Eq, "2.35-2");
+        assert!(eq.satisfied_by("2.35-2"));
+        assert!(!eq.satisfied_by("2.35-1"));
+
+        let ge = constraint(ConstraintOp::Ge, "2.35-2");
+        assert!(ge.satisfied_by("2.35-3"));
+        assert!(ge.satisfied_by("2.36-1"));
+        assert!(!ge.satisfied_by("2.35-1"));
+    }
+
+    #[test]
+    fn constraint_ops() {
+        assert!(constraint(ConstraintOp::Gt, "1.2").satisfied_by("1.10"));
+        assert!(!constraint(ConstraintOp::Gt, "1.2").satisfied_by("1.2-1"));
+        assert!(constraint(ConstraintOp::Lt, "1.10").satisfied_by("1.9"));
+        assert!(constraint(ConstraintOp::Le, "1.2").satisfied_by("1.2-5"));
+        assert!(!constraint(ConstraintOp::Le, "1.2").satisfied_by("1.3"));
+    }
+}
@@ -0,0 +1,455 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use nix::fcntl::{flock, FlockArg};
+use pyxis_parcel::{ParcelHandle, ReaderWriter};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use crate::{
+    get_parcel_path, get_provider,
+    imagebuild::resolve_configured,
+    providers::{alpm, upper},
+    Config, Manifest, PackageId, PyxisError, Result,
+};
+
+/// Name of the index file kept at the root of the parcel store
+const INDEX_FILE: &str = "index.yaml";
+/// Lock files at the root of the store. Anything that reads or writes parcels
+/// holds the store lock shared, and garbage collection holds it exclusive.
+/// The index lock serializes updates to the index.
+const STORE_LOCK: &str = ".lock";
+const INDEX_LOCK: &str = ".index.lock";
+
+/// An advisory lock on a file in the store, released when dropped
+pub(crate) struct StoreLock(File);
+
+impl StoreLock {
+    fn acquire(path: &Path, exclusive: bool) -> Result<StoreLock> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| PyxisError::parcel_io(path, e))?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .map_err(|e| PyxisError::parcel_io(path, e))?;
+        let arg = if exclusive {
+            FlockArg::LockExclusive
+        } else {
+            FlockArg::LockShared
+        };
+        flock(file.as_raw_fd(), arg).map_err(|e| PyxisError::parcel_io(path, e))?;
+        Ok(StoreLock(file))
+    }
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        let _ = flock(self.0.as_raw_fd(), FlockArg::Unlock);
+    }
+}
+
+/// Lock the whole store, shared for building and exclusive for removing parcels
+pub(crate) fn lock_store(config: &Config, exclusive: bool) -> Result<StoreLock> {
+    StoreLock::acquire(&config.parcel_root.join(STORE_LOCK), exclusive)
+}
+
+/// Lock a single parcel while it is built, so that concurrent builds of the
+/// same package wait for each other instead of racing
+pub(crate) fn lock_parcel(config: &Config, package: &PackageId) -> Result<StoreLock> {
+    let path = get_parcel_path(config, &package.provider, &package.name).with_extension("lock");
+    StoreLock::acquire(&path, true)
+}
+
+/// Create a temporary file next to `path`, to be renamed over it once complete
+pub(crate) fn temp_beside(path: &Path) -> Result<NamedTempFile> {
+    let parent = path.parent().unwrap_or_else(|| Path::new("."));
+    std::fs::create_dir_all(parent).map_err(|e| PyxisError::parcel_io(path, e))?;
+    tempfile::Builder::new()
+        .prefix(".")
+        .suffix(".tmp")
+        .tempfile_in(parent)
+        .map_err(|e| PyxisError::parcel_io(path, e))
+}
+
+/// Flush a completed temporary file to disk and move it into place
+pub(crate) fn persist(tmp: NamedTempFile, path: &Path) -> Result<()> {
+    tmp.as_file()
+        .sync_all()
+        .map_err(|e| PyxisError::parcel_io(path, e))?;
+    tmp.persist(path)
+        .map(|_| ())
+        .map_err(|e| PyxisError::parcel_io(path, e.error))
+}
+
+/// What the store knows about one parcel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ParcelInfo {
+    pub provider:  String,
+    pub name:      String,
+    pub version:   String,
+    pub depends:   Vec<String>,
+    /// Size of the parcel file in bytes
+    pub size:      u64,
+    /// When the parcel was written, in seconds since the epoch
+    pub built:     u64,
+    /// Whether the parcel carries an install scriptlet
+    pub scriptlet: bool,
+    /// What the parcel was built from, when not its provider's usual source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source:    Option<String>,
+}
+
+type Index = BTreeMap<String, ParcelInfo>;
+
+fn index_path(config: &Config) -> PathBuf {
+    config.parcel_root.join(INDEX_FILE)
+}
+
+fn load_index(config: &Config) -> Result<Index> {
+    let path = index_path(config);
+    match File::open(&path) {
+        Ok(file) => serde_yaml::from_reader(file).map_err(|e| PyxisError::parcel_io(&path, e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Index::new()),
+        Err(e) => Err(PyxisError::parcel_io(&path, e)),
+    }
+}
+
+fn save_index(config: &Config, index: &Index) -> Result<()> {
+    let path = index_path(config);
+    let tmp = temp_beside(&path)?;
+    serde_yaml::to_writer(tmp.as_file(), index).map_err(|e| PyxisError::parcel_io(&path, e))?;
+    persist(tmp, &path)
+}
+
+/// Apply a change to the index while holding the index lock
+fn update_index<F: FnOnce(&mut Index)>(config: &Config, f: F) -> Result<()> {
+    let _lock = StoreLock::acquire(&config.parcel_root.join(INDEX_LOCK), true)?;
+    let mut index = load_index(config)?;
+    f(&mut index);
+    save_index(config, &index)
+}
+
+fn has_scriptlet(parcel: &ParcelHandle, package: &PackageId) -> bool {
+    parcel
+        .select(PathBuf::from(format!(
+            ".PYXIS/{}/{}/.INSTALL",
+            package.provider, package.name
+        )))
+        .is_some()
+}
+
+fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Write a finished parcel to its place in the store and record it in the
+/// index. Every provider, including those registered from outside this
+/// crate, stores its parcels through here rather than writing to
+/// `get_parcel_path` itself. The parcel is written to a temporary file and
+/// renamed into place, so an interrupted build never leaves a truncated
+/// parcel behind.
+pub fn write_parcel(config: &Config, package: &PackageId, mut parcel: ParcelHandle) -> Result<()> {
+    let path = get_parcel_path(config, &package.provider, &package.name);
+    let pio = |e| PyxisError::parcel_io(&path, e);
+    let tmp = temp_beside(&path)?;
+    let file = tmp
+        .as_file()
+        .try_clone()
+        .map_err(|e| PyxisError::parcel_io(&path, e))?;
+    let scriptlet = has_scriptlet(&parcel, package);
+    let metadata = parcel.metadata();
+    let (version, depends) = (metadata.version.clone(), metadata.depends.clone());
+    let source = get_provider(&package.provider)?.source(config, &package.name)?;
+    parcel.set_file(Box::new(ReaderWriter::new(file)));
+    parcel.store().map_err(pio)?;
+    drop(parcel);
+
+    let size = tmp
+        .as_file()
+        .metadata()
+        .map_err(|e| PyxisError::parcel_io(&path, e))?
+        .len();
+    persist(tmp, &path)?;
+    update_index(config, |index| {
+        index.insert(
+            package.to_string(),
+            ParcelInfo {
+                provider: package.provider.clone(),
+                name: package.name.clone(),
+                version,
+                depends,
+                size,
+                built: epoch_secs(SystemTime::now()),
+                scriptlet,
+                source,
+            },
+        );
+    })
+}
+
+/// Read the details of a parcel from the parcel file itself, for parcels the
+/// index does not know about
+fn read_info(package: &PackageId, path: &Path) -> Result<ParcelInfo> {
+    let file = File::open(path).map_err(|e| PyxisError::parcel_io(path, e))?;
+    let meta = file
+        .metadata()
+        .map_err(|e| PyxisError::parcel_io(path, e))?;
+    let mut parcel = ParcelHandle::load(Box::new(ReaderWriter::new(file)))
+        .map_err(|e| PyxisError::parcel_io(path, e))?;
+    let scriptlet = has_scriptlet(&parcel, package);
+    let metadata = parcel.metadata();
+    let (version, depends) = (metadata.version.clone(), metadata.depends.clone());
+    Ok(ParcelInfo {
+        provider: package.provider.clone(),
+        name: package.name.clone(),
+        version,
+        depends,
+        size: meta.len(),
+        built: meta.modified().map(epoch_secs).unwrap_or(0),
+        scriptlet,
+        source: None,
+    })
+}
+
+/// List every parcel in the store. The index is brought up to date with the
+/// store on the way: parcels that were removed are dropped from it and
+/// parcels it is missing are read and added.
+pub fn list_parcels(config: &Config) -> Result<Vec<ParcelInfo>> {
+    let mut index = load_index(config)?;
+    let mut found = Index::new();
+    let mut added = false;
+    let providers = match std::fs::read_dir(&config.parcel_root) {
+        Ok(providers) => providers,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(PyxisError::parcel_io(&config.parcel_root, e)),
+    };
+    for provider in providers {
+        let provider = provider?;
+        if !provider.file_type()?.is_dir() {
+            continue;
+        }
+        for parcel in std::fs::read_dir(provider.path())? {
+            let path = parcel?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("parcel") {
+                continue;
+            }
+            let name = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+            let package = PackageId::new(&provider.file_name().to_string_lossy(), name);
+            let key = package.to_string();
+            let info = match index.remove(&key) {
+                Some(info) => info,
+                None => {
+                    added = true;
+                    read_info(&package, &path)?
+                }
+            };
+            found.insert(key, info);
+        }
+    }
+    // Whatever is left in the index no longer exists in the store
+    if added || !index.is_empty() {
+        update_index(config, |index| {
+            for key in index.keys().cloned().collect::<Vec<_>>() {
+                if !found.contains_key(&key) {
+                    index.remove(&key);
+                }
+            }
+            for (key, info) in &found {
+                index.entry(key.clone()).or_insert_with(|| info.clone());
+            }
+        })?;
+    }
+    Ok(found.into_values().collect())
+}
+
+/// What the store holds for a package, or `None` if it has no parcel for it
+pub(crate) fn stored_parcel(config: &Config, package: &PackageId) -> Result<Option<ParcelInfo>> {
+    let path = get_parcel_path(config, &package.provider, &package.name);
+    if !path.exists() {
+        return Ok(None);
+    }
+    match load_index(config)?.remove(&package.to_string()) {
+        Some(info) => Ok(Some(info)),
+        None => read_info(package, &path).map(Some),
+    }
+}
+
+/// Look up a single parcel by its `provider|name`
+pub fn parcel_info(config: &Config, package: &str) -> Result<ParcelInfo> {
+    let package = PackageId::parse(package)?;
+    stored_parcel(config, &package)?
+        .ok_or_else(|| PyxisError::Resolution(format!("no parcel for {} in the store", package)))
+}
+
+/// Delete a parcel from the store and drop it from the index
+fn remove_parcel(config: &Config, package: &PackageId) -> Result<()> {
+    let path = get_parcel_path(config, &package.provider, &package.name);
+    std::fs::remove_file(&path).map_err(|e| PyxisError::parcel_io(&path, e))?;
+    update_index(config, |index| {
+        index.remove(&package.to_string());
+    })
+}
+
+/// A file garbage collection removed from the package cache
+#[derive(Serialize, Debug, Clone)]
+pub struct CachedFile {
+    pub path: PathBuf,
+    /// Size of the file in bytes
+    pub size: u64,
+}
+
+/// What garbage collection removed, or would have removed
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct GcReport {
+    pub parcels: Vec<ParcelInfo>,
+    pub cached:  Vec<CachedFile>,
+}
+
+impl GcReport {
+    /// Bytes freed, or that would be freed
+    pub fn freed(&self) -> u64 {
+        self.parcels.iter().map(|p| p.size).sum::<u64>()
+            + self.cached.iter().map(|c| c.size).sum::<u64>()
+    }
+}
+
+/// Remove every parcel not needed to build the given manifests. A parcel is
+/// needed if it is in the closure of a manifest at the version its provider
+/// currently offers, or if it is the upper parcel of a manifest. Downloaded
+/// packages in the package cache are removed on the same terms, as are the
+/// lock files of parcels no longer in the store. Anything written less than
+/// `keep_days` days ago is always kept. With `dry_run` nothing is deleted.
+pub fn store_gc(
+    config: &Config,
+    manifests: &[&str],
+    keep_days: Option<u64>,
+    dry_run: bool,
+) -> Result<GcReport> {
+    let _lock = lock_store(config, !dry_run)?;
+    // The versions of each package that some manifest needs. Manifests may use
+    // different repositories, so a package can be needed at several versions.
+    let mut live: HashMap<PackageId, HashSet<String>> = HashMap::new();
+    // The package cache files those versions were built from
+    let mut live_cache: HashSet<String> = HashSet::new();
+    for manifest in manifests {
+        let manifest = Manifest::load(Path::new(manifest))?;
+        let mconfig = &manifest.configure(config);
+        let closure = resolve_configured(mconfig, &manifest)?.packages;
+        let packages: Vec<PackageId> = closure.into_iter().collect();
+        // Upper parcels carry their own name as their version
+        let upper_name = upper::upper_name(mconfig, &packages)?;
+        live.entry(PackageId::new(upper::PREFIX, &upper_name))
+            .or_default()
+            .insert(upper_name);
+        for package in packages {
+            let version = get_provider(&package.provider)?.get_version(mconfig, &package.name)?;
+            if package.provider == alpm::PREFIX {
+                live_cache.extend(alpm::cache_files(mconfig, &package.name)?);
+            }
+            live.entry(package).or_default().insert(version);
+        }
+    }
+
+    let cutoff = keep_days.map(|days| epoch_secs(SystemTime::now()).saturating_sub(days * 86400));
+    let mut report = GcReport::default();
+    for parcel in list_parcels(config)? {
+        if cutoff.is_some_and(|cutoff| parcel.built >= cutoff) {
+            continue;
+        }
+        let package = PackageId::new(&parcel.provider, &parcel.name);
+        if live
+            .get(&package)
+            .is_some_and(|versions| versions.contains(&parcel.version))
+        {
+            continue;
+        }
+        if !dry_run {
+            remove_parcel(config, &package)?;
+        }
+        report.parcels.push(parcel);
+    }
+    report.cached = gc_cache(config, &live_cache, cutoff, dry_run)?;
+    if !dry_run {
+        remove_stale_locks(config)?;
+    }
+    Ok(report)
+}
+
+/// Remove the files in the package cache that are not in `live`, keeping any
+/// written since `cutoff`
+fn gc_cache(
+    config: &Config,
+    live: &HashSet<String>,
+    cutoff: Option<u64>,
+    dry_run: bool,
+) -> Result<Vec<CachedFile>> {
+    let cache_dir = config.cache_dir();
+    let entries = match std::fs::read_dir(&cache_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(PyxisError::parcel_io(&cache_dir, e)),
+    };
+    let mut removed = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| PyxisError::parcel_io(&cache_dir, e))?;
+        let path = entry.path();
+        let meta = entry
+            .metadata()
+            .map_err(|e| PyxisError::parcel_io(&path, e))?;
+        if !meta.is_file() || live.contains(&*entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        let modified = meta.modified().map(epoch_secs).unwrap_or(0);
+        if cutoff.is_some_and(|cutoff| modified >= cutoff) {
+            continue;
+        }
+        if !dry_run {
+            std::fs::remove_file(&path).map_err(|e| PyxisError::parcel_io(&path, e))?;
+        }
+        removed.push(CachedFile {
+            path,
+            size: meta.len(),
+        });
+    }
+    Ok(removed)
+}
+
+/// Remove the lock files of parcels that are no longer in the store. Parcel
+/// locks are only taken under the shared store lock, so while garbage
+/// collection holds it exclusively none of them can be in use.
+fn remove_stale_locks(config: &Config) -> Result<()> {
+    let root = &config.parcel_root;
+    let providers = match std::fs::read_dir(root) {
+        Ok(providers) => providers,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(PyxisError::parcel_io(root, e)),
+    };
+    for provider in providers {
+        let provider = provider?;
+        if !provider.file_type()?.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(provider.path())? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some("lock")
+                && !path.with_extension("parcel").exists()
+            {
+                std::fs::remove_file(&path).map_err(|e| PyxisError::parcel_io(&path, e))?;
+            }
+        }
+    }
+    Ok(())
+}
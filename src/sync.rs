@@ -0,0 +1,126 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use ::alpm::SigLevel;
+use log::{debug, info};
+use serde::Serialize;
+
+use crate::{
+    download::{download, download_mirrored},
+    providers::alpm,
+    store::{lock_store, persist, temp_beside},
+    Config, Manifest, PyxisError, Result,
+};
+
+/// A sync database downloaded by `pyxis sync`
+#[derive(Serialize, Debug, Clone)]
+pub struct SyncedDb {
+    pub repo: String,
+    /// The URL the database was downloaded from
+    pub url:  String,
+    /// Where the database was stored
+    pub path: PathBuf,
+    /// Size of the database in bytes
+    pub size: u64,
+}
+
+/// Download the sync database of every repository the given manifests use,
+/// or of every configured repository if no manifest is given, replacing the
+/// previous copies. Repositories whose SigLevel checks databases also get the
+/// database signature, from the same mirror.
+pub fn sync_databases(config: &Config, manifests: &[&str]) -> Result<Vec<SyncedDb>> {
+    // Resolution reads the databases, so keep builds out while they change
+    let _lock = lock_store(config, true)?;
+    let configs = match manifests {
+        [] => vec![config.clone()],
+        manifests => manifests
+            .iter()
+            .map(|manifest| Ok(Manifest::load(Path::new(manifest))?.configure(config)))
+            .collect::<Result<_>>()?,
+    };
+    let mut synced = Vec::new();
+    let mut db_dirs = HashSet::new();
+    for config in &configs {
+        // Manifests using the same repositories share their databases
+        let db_dir = config.db_dir()?;
+        if db_dirs.insert(db_dir.clone()) {
+            synced.extend(sync_repositories(config, &db_dir)?);
+        }
+    }
+    // Make the next resolution open the new databases
+    alpm::reset();
+    Ok(synced)
+}
+
+/// Download the sync databases of the repositories of `config` into `db_dir`
+fn sync_repositories(config: &Config, db_dir: &Path) -> Result<Vec<SyncedDb>> {
+    let sync_dir = db_dir.join("sync");
+    let mut synced = Vec::new();
+    for repo in config.repositories()? {
+        let filename = format!("{}.db", repo.name);
+        info!("Syncing {}", repo.name);
+
+        let path = sync_dir.join(&filename);
+        let mut tmp = temp_beside(&path)?;
+        let url = download_mirrored(
+            config,
+            &filename,
+            &repo.servers,
+            &filename,
+            tmp.as_file_mut(),
+        )?;
+        let size = tmp
+            .as_file()
+            .metadata()
+            .map_err(|e| PyxisError::fetch(&filename, e))?
+            .len();
+
+        let sig_name = format!("{}.sig", filename);
+        let sig_path = sync_dir.join(&sig_name);
+        let mut sig = None;
+        if repo.sig_level.contains(SigLevel::DATABASE) {
+            let server = url.strip_suffix(&format!("/{}", filename)).unwrap_or(&url);
+            let mut sig_tmp = temp_beside(&sig_path)?;
+            // Most repositories publish no database signatures, so when one
+            // is optional a single attempt is enough
+            let fetched = if repo.sig_level.contains(SigLevel::DATABASE_OPTIONAL) {
+                download(&format!("{}/{}", server, sig_name), sig_tmp.as_file_mut())
+                    .map_err(|e| debug!("No signature for {}: {}", filename, e))
+                    .is_ok()
+            } else {
+                download_mirrored(
+                    config,
+                    &sig_name,
+                    &[server.to_owned()],
+                    &sig_name,
+                    sig_tmp.as_file_mut(),
+                )?;
+                true
+            };
+            if fetched {
+                sig = Some(sig_tmp);
+            }
+        }
+
+        persist(tmp, &path)?;
+        match sig {
+            Some(sig) => persist(sig, &sig_path)?,
+            // A signature from an earlier sync does not match the new database
+            None => match std::fs::remove_file(&sig_path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(PyxisError::fetch(&sig_name, e))
+                }
+                _ => {}
+            },
+        }
+        synced.push(SyncedDb {
+            repo: repo.name,
+            url,
+            path,
+            size,
+        });
+    }
+    Ok(synced)
+}